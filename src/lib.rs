@@ -1,15 +1,24 @@
 pub mod samples {
+    use crate::primers::{Direction, Primer};
     use std::collections::{HashMap, HashSet};
     // use std::error::Error;
     use std::fmt::{self};
     use std::io::{self, BufRead};
 
+    #[allow(dead_code)]
     pub struct SampleData {
         name: String,
         is_control: bool,
     }
 
-    #[derive(Eq, PartialEq, Hash)]
+    impl SampleData {
+        /// Returns the sample name.
+        pub fn name(&self) -> &str {
+            &self.name
+        }
+    }
+
+    #[derive(Debug, Clone, Eq, PartialEq, Hash)]
     pub struct PrimerPair {
         pub forward: String,
         pub reverse: String,
@@ -78,18 +87,53 @@ pub mod samples {
                     forward: fwd.to_string(),
                     reverse: rev.to_string(),
                 })
-                .map_or(None, |s| Some(s.name.to_owned()))
+                .map(|s| s.name.to_owned())
         }
 
         pub fn contains_sample(&self, primers: &PrimerPair) -> bool {
             self.sample_table.contains_key(primers)
         }
+
+        /// Check the table against a primer table.
+        ///
+        /// Returns a description of each problem found: primers used in the
+        /// table which are missing from `primers`, or which are used in the
+        /// wrong direction.
+        pub fn check_primers(&self, primers: &[Primer]) -> Vec<String> {
+            let mut problems = Vec::new();
+            for (labels, direction) in [
+                (&self.forward_primers, Direction::Forward),
+                (&self.reverse_primers, Direction::Reverse),
+            ] {
+                let mut labels: Vec<_> = labels.iter().collect();
+                labels.sort();
+                for label in labels {
+                    match primers.iter().find(|p| p.label() == label) {
+                        None => problems.push(format!("primer {label} not in primers file")),
+                        Some(p) if p.direction() != direction => problems.push(format!(
+                            "primer {label} is used as {:?} but declared {:?}",
+                            direction,
+                            p.direction()
+                        )),
+                        Some(_) => {}
+                    }
+                }
+            }
+            problems
+        }
+
+        /// Iterate over the primer pairs & samples, in no particular order.
+        pub fn iter(&self) -> impl Iterator<Item = (&PrimerPair, &SampleData)> {
+            self.sample_table.iter()
+        }
     }
 
     impl fmt::Display for SamplesTable {
         fn fmt(&self, dest: &mut fmt::Formatter) -> fmt::Result {
             if dest.alternate() {
-                for (primers, sample) in &self.sample_table {
+                let mut entries: Vec<_> = self.sample_table.iter().collect();
+                entries.sort_by_key(|(p, _)| (&p.forward, &p.reverse));
+                for (primers, sample) in entries {
                     writeln!(
                         dest,
                         "{}\t{}\t{}",
@@ -97,14 +141,20 @@ pub mod samples {
                     )?;
                 }
             } else {
-                write!(dest, "SamplesTable in standard format.")?;
-                for rev in &self.reverse_primers {
+                // Same layout as read by read_wide_table: reverse primers in the
+                // first row (which begins with whitespace), forward primers in
+                // the first column.
+                let mut forward: Vec<_> = self.forward_primers.iter().collect();
+                let mut reverse: Vec<_> = self.reverse_primers.iter().collect();
+                forward.sort();
+                reverse.sort();
+                for rev in &reverse {
                     write!(dest, "\t{}", rev)?;
                 }
-                write!(dest, "\n")?;
-                for fwd in &self.forward_primers {
+                writeln!(dest)?;
+                for fwd in &forward {
                     write!(dest, "{fwd}")?;
-                    for rev in &self.reverse_primers {
+                    for rev in &reverse {
                         write!(
                             dest,
                             "\t{}",
@@ -112,6 +162,7 @@ pub mod samples {
                                 .unwrap_or("".to_string())
                         )?;
                     }
+                    writeln!(dest)?;
                 }
             }
             Ok(())
@@ -119,7 +170,7 @@ pub mod samples {
     }
 
     pub fn fake_samples_table(succeed: bool) -> Result<SamplesTable, std::io::Error> {
-        if succeed == true {
+        if succeed {
             let mut samples: SamplesTable = SamplesTable::new();
             samples.insert(
                 PrimerPair {
@@ -176,11 +227,10 @@ pub mod samples {
             ));
         }
 
-        while let Some(line) = lines.next() {
+        for line in lines {
             let mut elements = line.split_ascii_whitespace();
             if let Some(fp) = elements.next() {
-                let mut column = 0;
-                while let Some(sample) = elements.next() {
+                for (column, sample) in elements.enumerate() {
                     let rp = rev_primers[column].clone();
                     samples_table.insert(
                         PrimerPair {
@@ -192,7 +242,6 @@ pub mod samples {
                             is_control: false,
                         },
                     );
-                    column += 1;
                 }
                 fwd_primers.push(fp.to_string());
             }
@@ -201,6 +250,190 @@ pub mod samples {
         Ok(samples_table)
     }
 
+    impl Default for SamplesTable {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    /// An inclusive range of lengths, written `MIN-MAX`.
+    ///
+    /// # Examples
+    /// ```
+    /// use myfq::samples::LengthRange;
+    /// let range: LengthRange = "100-250".parse().unwrap();
+    /// assert!(range.contains(100) && range.contains(250));
+    /// assert!(!range.contains(251));
+    /// assert!("250-100".parse::<LengthRange>().is_err());
+    /// ```
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct LengthRange {
+        pub min: usize,
+        pub max: usize,
+    }
+
+    impl LengthRange {
+        pub fn contains(&self, length: usize) -> bool {
+            (self.min..=self.max).contains(&length)
+        }
+    }
+
+    impl std::str::FromStr for LengthRange {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let (min, max) = s
+                .split_once('-')
+                .ok_or_else(|| format!("length range '{s}' is not MIN-MAX"))?;
+            let parse = |n: &str| {
+                n.trim()
+                    .parse::<usize>()
+                    .map_err(|e| format!("length range '{s}': {e}"))
+            };
+            let (min, max) = (parse(min)?, parse(max)?);
+            if min > max {
+                return Err(format!("length range '{s}' is empty"));
+            }
+            Ok(LengthRange { min, max })
+        }
+    }
+
+    /// Expected lengths of the insert between the primers & of the whole read.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    pub struct LengthLimits {
+        pub insert: Option<LengthRange>,
+        pub read: Option<LengthRange>,
+    }
+
+    impl LengthLimits {
+        /// Which length, `insert` or `read`, is outside its range, if any.
+        pub fn check(&self, insert: usize, read: usize) -> Option<&'static str> {
+            if self.insert.is_some_and(|r| !r.contains(insert)) {
+                Some("insert")
+            } else if self.read.is_some_and(|r| !r.contains(read)) {
+                Some("read")
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Targets of a multiplex amplicon panel, & expected lengths, by primer pair.
+    ///
+    /// A read's target is looked up by the primer pair it was assigned by.  A
+    /// sample listed under several primer pairs, as in a multiplex panel, can
+    /// therefore have reads in several targets.
+    ///
+    /// # Examples
+    /// ```
+    /// use myfq::samples::*;
+    /// let mut panel = Panel::new();
+    /// panel.insert_by_names("oVK001", "oVK010", "BRCA1_ex2");
+    /// assert_eq!(panel.get_target_by_names("oVK001", "oVK010"), Some("BRCA1_ex2"));
+    /// ```
+    #[derive(Default)]
+    pub struct Panel {
+        targets: HashMap<PrimerPair, String>,
+        lengths: HashMap<PrimerPair, LengthLimits>,
+    }
+
+    impl Panel {
+        pub fn new() -> Panel {
+            Panel {
+                targets: HashMap::new(),
+                lengths: HashMap::new(),
+            }
+        }
+
+        pub fn insert_by_names(&mut self, forward: &str, reverse: &str, target: &str) -> &mut Self {
+            self.targets.insert(
+                PrimerPair {
+                    forward: forward.to_string(),
+                    reverse: reverse.to_string(),
+                },
+                target.to_string(),
+            );
+            self
+        }
+
+        /// Set the expected lengths of the products of a primer pair.
+        pub fn set_lengths_by_names(
+            &mut self,
+            forward: &str,
+            reverse: &str,
+            limits: LengthLimits,
+        ) -> &mut Self {
+            self.lengths.insert(
+                PrimerPair {
+                    forward: forward.to_string(),
+                    reverse: reverse.to_string(),
+                },
+                limits,
+            );
+            self
+        }
+
+        pub fn get(&self, primers: &PrimerPair) -> Option<&str> {
+            self.targets.get(primers).map(String::as_str)
+        }
+
+        pub fn get_target_by_names(&self, fwd: &str, rev: &str) -> Option<&str> {
+            self.get(&PrimerPair {
+                forward: fwd.to_string(),
+                reverse: rev.to_string(),
+            })
+        }
+
+        pub fn lengths(&self, primers: &PrimerPair) -> Option<&LengthLimits> {
+            self.lengths.get(primers)
+        }
+
+        /// Iterate over the primer pairs & targets, in no particular order.
+        pub fn iter(&self) -> impl Iterator<Item = (&PrimerPair, &str)> {
+            self.targets.iter().map(|(p, t)| (p, t.as_str()))
+        }
+    }
+
+    /// Read a panel definition: one primer pair per line, as whitespace
+    /// separated forward primer, reverse primer & target name, `.` for none.
+    /// Expected lengths may follow as `insert=MIN-MAX` and `read=MIN-MAX`.
+    pub fn read_panel(rdr: Box<dyn BufRead>) -> Result<Panel, std::io::Error> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut panel = Panel::new();
+        for line in rdr.lines() {
+            let line = line?;
+            if line.trim().is_empty() || line.trim_start().starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_ascii_whitespace().collect();
+            let [fwd, rev, target, lengths @ ..] = fields.as_slice() else {
+                return Err(invalid(format!(
+                    "Panel line needs forward, reverse & target: '{line}'"
+                )));
+            };
+            if *target != "." {
+                panel.insert_by_names(fwd, rev, target);
+            }
+            let mut limits = LengthLimits::default();
+            for field in lengths {
+                let range = |text: &str| text.parse::<LengthRange>().map_err(invalid);
+                match field.split_once('=') {
+                    Some(("insert", text)) => limits.insert = Some(range(text)?),
+                    Some(("read", text)) => limits.read = Some(range(text)?),
+                    _ => {
+                        return Err(invalid(format!(
+                            "Unknown panel field '{field}' in '{line}'"
+                        )))
+                    }
+                }
+            }
+            if limits != LengthLimits::default() {
+                panel.set_lengths_by_names(fwd, rev, limits);
+            }
+        }
+        Ok(panel)
+    }
+
     #[test]
     fn create_samples_table() {
         let mut t: SamplesTable = SamplesTable::new();
@@ -236,13 +469,71 @@ pub mod samples {
             },
         );
 
+        use std::fmt::Write;
         let mut s: String = String::new();
-        write!(s, "{}", t);
+        write!(s, "{}", t).unwrap();
         s.find("p001").expect("fwd not found");
         s.find("p010").expect("rev not found");
         s.find("sample_1").expect("name not found");
     }
 
+    #[test]
+    fn wide_table_round_trip() {
+        let mut t = SamplesTable::new();
+        t.insert_by_names("p001", "p010", "s1")
+            .insert_by_names("p001", "p020", "s2")
+            .insert_by_names("p002", "p010", "s3")
+            .insert_by_names("p002", "p020", "s4");
+        let rdr = Box::new(io::Cursor::new(format!("{}", t))) as Box<dyn BufRead>;
+        let t2 = read_wide_table(rdr).unwrap();
+        assert_eq!(format!("{:#}", t2), format!("{:#}", t));
+    }
+
+    #[test]
+    fn check_primers_against_table() {
+        let t = fake_samples_table(true).unwrap();
+        let primers = vec![
+            Primer::new("oVK001", b"ACGT", b"", Direction::Forward),
+            Primer::new("oVK002", b"ACGT", b"", Direction::Forward),
+            Primer::new("oVK010", b"ACGT", b"", Direction::Forward),
+        ];
+        let problems = t.check_primers(&primers);
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].contains("oVK010"));
+        assert!(problems[1].contains("oVK020"));
+    }
+
+    #[test]
+    fn read_panel_lines() {
+        let text = "# panel\noVK001\toVK010\tamp1\n\noVK002 oVK020 amp2\n";
+        let panel = read_panel(Box::new(io::Cursor::new(text))).unwrap();
+        assert_eq!(panel.get_target_by_names("oVK002", "oVK020"), Some("amp2"));
+        assert_eq!(panel.iter().count(), 2);
+        let bad = "oVK001\toVK010\n";
+        assert!(read_panel(Box::new(io::Cursor::new(bad))).is_err());
+    }
+
+    #[test]
+    fn read_panel_lengths() {
+        let text =
+            "oVK001 oVK010 amp1 insert=100-200\noVK002 oVK010 . read=150-300 insert=90-110\n";
+        let panel = read_panel(Box::new(io::Cursor::new(text))).unwrap();
+        let pair = |f: &str| PrimerPair {
+            forward: f.to_string(),
+            reverse: "oVK010".to_string(),
+        };
+        let limits = panel.lengths(&pair("oVK001")).unwrap();
+        assert_eq!(limits.check(150, 1000), None);
+        assert_eq!(limits.check(99, 150), Some("insert"));
+        assert_eq!(panel.get(&pair("oVK002")), None);
+        assert_eq!(
+            panel.lengths(&pair("oVK002")).unwrap().check(100, 120),
+            Some("read")
+        );
+        let bad = "oVK001 oVK010 amp1 length=100-200\n";
+        assert!(read_panel(Box::new(io::Cursor::new(bad))).is_err());
+    }
+
     #[test]
     fn write_narrow_table() {
         let mut t: SamplesTable = SamplesTable::new();
@@ -257,8 +548,9 @@ pub mod samples {
             },
         );
 
+        use std::fmt::Write;
         let mut s: String = String::new();
-        write!(s, "{:#}", t);
+        write!(s, "{:#}", t).unwrap();
         s.find("p001").expect("fwd not found");
         s.find("p010").expect("rev not found");
         s.find("sample_1").expect("name not found");
@@ -269,6 +561,7 @@ pub mod primers {
 
     use bio::alphabets::dna;
     use bio::pattern_matching::bom::BOM;
+    use bio::pattern_matching::myers::long::Myers;
     use serde::Deserialize;
     use std::io::Read;
 
@@ -279,10 +572,31 @@ pub mod primers {
         sequence: String,
         barcode: String,
         direction: String,
+        #[serde(default)]
+        spacer: Option<String>,
     }
 
     type PrimerTable = Vec<Primer>;
 
+    /// Parse a spacer length range, `N` or `N-M`.
+    fn parse_spacer(text: &str) -> Result<(usize, usize), String> {
+        let parse = |n: &str| {
+            n.trim()
+                .parse::<usize>()
+                .map_err(|_| format!("Invalid spacer range '{text}'."))
+        };
+        match text.split_once('-') {
+            Some((lo, hi)) => Ok((parse(lo)?, parse(hi)?)),
+            None => parse(text).map(|n| (n, n)),
+        }
+    }
+
+    /// Read a primer table: tab separated label, sequence, barcode &
+    /// direction (`F` or `R`), with an optional fifth column giving the range
+    /// of spacer lengths before the primer, e.g. `0-7`.
+    ///
+    /// Rows sharing a label form a primer pool: they are numbered as variants
+    /// 0, 1, ... of one logical primer, and must have the same direction.
     pub fn read_primer_table<R: Read>(
         rdr: &mut R,
     ) -> Result<PrimerTable, Box<dyn std::error::Error>> {
@@ -290,10 +604,15 @@ pub mod primers {
         let mut primer_reader = csv::ReaderBuilder::new()
             .has_headers(false)
             .delimiter(b'\t')
+            .flexible(true)
             .from_reader(rdr);
         for result in primer_reader.deserialize() {
-            let primer_record: PrimerRecord = result.expect("Bad primer record.");
-            let primer = Primer::new(
+            let primer_record: PrimerRecord = result?;
+            let spacer = match primer_record.spacer.as_deref() {
+                None | Some("") => None,
+                Some(text) => Some(parse_spacer(text)?),
+            };
+            let mut primer = Primer::new(
                 primer_record.label.as_str(),
                 &primer_record.sequence.into_bytes(),
                 &primer_record.barcode.into_bytes(),
@@ -303,6 +622,17 @@ pub mod primers {
                     Direction::Reverse
                 },
             );
+            if let Some((min, max)) = spacer {
+                primer = primer.with_spacer(min, max);
+            }
+            let pool: Vec<&Primer> = primer_table
+                .iter()
+                .filter(|p| p.label() == primer.label())
+                .collect();
+            if pool.iter().any(|p| p.direction() != primer.direction()) {
+                return Err(format!("Primer pool {} mixes directions.", primer.label()).into());
+            }
+            primer.variant = pool.len();
             match primer.check() {
                 Err(e) => eprintln!("Primer read error: {e}"),
                 Ok(_) => primer_table.push(primer),
@@ -336,6 +666,145 @@ pub mod primers {
         }
     }
 
+    /// Parameters for quality-aware approximate primer matching.
+    ///
+    /// A mismatch at a read base with Phred quality `q` costs `1 - 10^(-q/10)`,
+    /// the probability that the base call is correct, so a mismatch at Q2 costs
+    /// about 0.37 while a mismatch at Q40 costs nearly 1.  Read bases with a
+    /// quality below `min_quality`, and `N` calls, match any primer base.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use myfq::primers::*;
+    /// let qm = QualityMatch { max_penalty: 1.0, min_quality: 10 };
+    /// assert_eq!(qm.penalty(b'A', b'A', b'I'), 0.0);
+    /// assert_eq!(qm.penalty(b'A', b'C', b'#'), 0.0);
+    /// assert!(qm.penalty(b'A', b'C', b'I') > 0.99);
+    /// ```
+    #[derive(Debug, PartialEq, Copy, Clone)]
+    pub struct QualityMatch {
+        /// Largest summed mismatch penalty accepted for a hit.
+        pub max_penalty: f64,
+        /// Read bases below this Phred quality are treated as wildcards.
+        pub min_quality: u8,
+    }
+
+    impl Default for QualityMatch {
+        fn default() -> Self {
+            Self {
+                max_penalty: 0.0,
+                min_quality: 0,
+            }
+        }
+    }
+
+    impl QualityMatch {
+        /// Penalty for aligning primer base `expected` to read base `observed`
+        /// with Phred+33 encoded quality `qual`.
+        pub fn penalty(&self, expected: u8, observed: u8, qual: u8) -> f64 {
+            let observed = observed.to_ascii_uppercase();
+            if expected.to_ascii_uppercase() == observed || observed == b'N' {
+                return 0.0;
+            }
+            let q = qual.saturating_sub(33);
+            if q < self.min_quality {
+                return 0.0;
+            }
+            1.0 - 10f64.powf(-(q as f64) / 10.0)
+        }
+
+        /// Find every placement of `pattern` in `seq` whose summed penalty is
+        /// at most `max_penalty`.
+        ///
+        /// Overlapping placements are collapsed to the one with the lowest
+        /// penalty (the leftmost on ties).
+        pub fn find_all(&self, pattern: &[u8], seq: &[u8], qual: &[u8]) -> Vec<PrimerHit> {
+            let mut hits: Vec<PrimerHit> = Vec::new();
+            if pattern.is_empty() || seq.len() < pattern.len() || qual.len() < seq.len() {
+                return hits;
+            }
+            for start in 0..=(seq.len() - pattern.len()) {
+                let mut penalty = 0.0;
+                for (i, &expected) in pattern.iter().enumerate() {
+                    penalty += self.penalty(expected, seq[start + i], qual[start + i]);
+                    if penalty > self.max_penalty {
+                        break;
+                    }
+                }
+                if penalty > self.max_penalty {
+                    continue;
+                }
+                let hit = PrimerHit {
+                    start,
+                    end: start + pattern.len(),
+                    penalty,
+                };
+                match hits.last_mut() {
+                    Some(last) if last.end > start => {
+                        if penalty < last.penalty {
+                            *last = hit;
+                        }
+                    }
+                    _ => hits.push(hit),
+                }
+            }
+            hits
+        }
+    }
+
+    /// The match of `pattern` in `text` with the fewest edits, at most
+    /// `max_edits`; ties go to the leftmost.
+    fn best_edit_match(pattern: &[u8], text: &[u8], max_edits: usize) -> Option<PrimerHit> {
+        if pattern.is_empty() {
+            return None;
+        }
+        let mut myers = Myers::<u64>::new(pattern);
+        myers
+            .find_all(text, max_edits)
+            .min_by_key(|&(start, _, dist)| (dist, start))
+            .map(|(start, end, dist)| PrimerHit {
+                start,
+                end,
+                penalty: dist as f64,
+            })
+    }
+
+    /// Non-overlapping matches of `pattern` in `text` with at most
+    /// `max_edits` edits, in order of position.  Of overlapping matches the
+    /// one with the fewest edits is kept, the leftmost on ties.
+    fn all_edit_matches(pattern: &[u8], text: &[u8], max_edits: usize) -> Vec<PrimerHit> {
+        if pattern.is_empty() {
+            return Vec::new();
+        }
+        let mut myers = Myers::<u64>::new(pattern);
+        let mut matches: Vec<(usize, usize, usize)> = myers.find_all(text, max_edits).collect();
+        matches.sort_by_key(|&(start, _, dist)| (dist, start));
+        let mut hits: Vec<PrimerHit> = Vec::new();
+        for (start, end, dist) in matches {
+            if hits.iter().all(|h| end <= h.start || start >= h.end) {
+                hits.push(PrimerHit {
+                    start,
+                    end,
+                    penalty: dist as f64,
+                });
+            }
+        }
+        hits.sort_by_key(|h| h.start);
+        hits
+    }
+
+    /// Location of a primer sequence within a read.
+    ///
+    /// `start..end` is the half-open range of read positions covered by the
+    /// primer; `penalty` is the summed mismatch penalty (0 for exact matches).
+    #[derive(Debug, PartialEq, Copy, Clone)]
+    pub struct PrimerHit {
+        pub start: usize,
+        pub end: usize,
+        pub penalty: f64,
+    }
+
     /// Data structure to hold a single primer.
     ///
     /// # Examples
@@ -356,6 +825,8 @@ pub mod primers {
         sequence_rc: Vec<u8>,
         barcode: Vec<u8>,
         direction: Direction,
+        spacer: Option<(usize, usize)>,
+        variant: usize,
         searcher: BOM,
         searcher_rc: BOM,
     }
@@ -373,12 +844,44 @@ pub mod primers {
                 sequence: sequence.to_vec(),
                 barcode: barcode.to_vec(),
                 direction,
+                spacer: None,
+                variant: 0,
                 sequence_rc: dna::revcomp(sequence.to_owned()),
                 searcher: BOM::new(sequence.to_owned()),
                 searcher_rc: BOM::new(dna::revcomp(sequence.to_owned())),
             }
         }
 
+        /// Declare that between `min` and `max` random bases (a heterogeneity
+        /// spacer) precede the primer at the 5' end of a read.
+        ///
+        /// # Examples
+        ///
+        /// ```
+        /// use myfq::primers::*;
+        /// let p = Primer::new("primer", b"GATACA", b"", Direction::Forward).with_spacer(0, 7);
+        /// assert_eq!(p.spacer(), Some((0, 7)));
+        /// assert!(p.spacer_allows(3));
+        /// assert!(!p.spacer_allows(8));
+        /// ```
+        pub fn with_spacer(mut self, min: usize, max: usize) -> Primer {
+            self.spacer = Some((min, max));
+            self
+        }
+
+        /// Returns the range of spacer lengths, if the primer has a spacer.
+        pub fn spacer(&self) -> Option<(usize, usize)> {
+            self.spacer
+        }
+
+        /// Whether the primer may start `offset` bases from the 5' end of a read.
+        ///
+        /// Primers without a spacer may start anywhere.
+        pub fn spacer_allows(&self, offset: usize) -> bool {
+            self.spacer
+                .is_none_or(|(min, max)| min <= offset && offset <= max)
+        }
+
         /// Check the validity of the Primer object after creation.
         ///
         /// The label must not be empty & the sequence must consist of valid DNA
@@ -392,6 +895,9 @@ pub mod primers {
             if !dna::alphabet().is_word(self.sequence.to_owned()) {
                 return Err("Invalid DNA sequence.");
             }
+            if self.spacer.is_some_and(|(min, max)| min > max) {
+                return Err("Invalid spacer range.");
+            }
             Ok(())
         }
 
@@ -411,14 +917,137 @@ pub mod primers {
         /// assert!(p.is_found_in(sequence1));
         /// assert!(!p.is_found_in(sequence2));
         /// ```
-
         pub fn is_found_in(&self, seq: &[u8]) -> bool {
-            !self.searcher.find_all(seq).next().is_none()
+            self.searcher.find_all(seq).next().is_some()
         }
 
         /// Search in `seq` for the reverse complement of the primer sequence.
         pub fn is_found_in_rc(&self, seq: &[u8]) -> bool {
-            !self.searcher_rc.find_all(seq).next().is_none()
+            self.searcher_rc.find_all(seq).next().is_some()
+        }
+
+        /// Returns the location of every exact occurrence of the primer sequence in `seq`.
+        pub fn find_in(&self, seq: &[u8]) -> Vec<PrimerHit> {
+            self.searcher
+                .find_all(seq)
+                .map(|start| PrimerHit {
+                    start,
+                    end: start + self.sequence.len(),
+                    penalty: 0.0,
+                })
+                .collect()
+        }
+
+        /// Returns the location of every exact occurrence of the reverse
+        /// complement of the primer sequence in `seq`.
+        pub fn find_in_rc(&self, seq: &[u8]) -> Vec<PrimerHit> {
+            self.searcher_rc
+                .find_all(seq)
+                .map(|start| PrimerHit {
+                    start,
+                    end: start + self.sequence_rc.len(),
+                    penalty: 0.0,
+                })
+                .collect()
+        }
+
+        /// Returns the best match of the primer sequence within the first `window`
+        /// bases of `seq`, with at most `max_edits` substitutions, insertions &
+        /// deletions.  The penalty of the hit is its edit distance.
+        ///
+        /// For error-prone long reads, where only the ends need searching.
+        ///
+        /// # Examples
+        ///
+        /// ```
+        /// use myfq::primers::*;
+        /// let p = Primer::new("primer", b"GATACAGGTT", b"", Direction::Forward);
+        /// let seq = b"CCGATCAGGTTAAAAAAAAAAAAAAAAAAAAA";
+        /// let hit = p.find_near_start(seq, 15, 2).unwrap();
+        /// assert_eq!((hit.start, hit.end, hit.penalty), (2, 11, 1.0));
+        /// assert_eq!(p.find_near_start(seq, 8, 2), None);
+        /// ```
+        pub fn find_near_start(
+            &self,
+            seq: &[u8],
+            window: usize,
+            max_edits: usize,
+        ) -> Option<PrimerHit> {
+            best_edit_match(&self.sequence, &seq[..window.min(seq.len())], max_edits)
+        }
+
+        /// Returns the best match of the reverse complement of the primer
+        /// sequence within the last `window` bases of `seq`, as for
+        /// [Primer::find_near_start].
+        pub fn find_rc_near_end(
+            &self,
+            seq: &[u8],
+            window: usize,
+            max_edits: usize,
+        ) -> Option<PrimerHit> {
+            let offset = seq.len().saturating_sub(window);
+            best_edit_match(&self.sequence_rc, &seq[offset..], max_edits).map(|hit| PrimerHit {
+                start: hit.start + offset,
+                end: hit.end + offset,
+                ..hit
+            })
+        }
+
+        /// Returns every match of the primer sequence in `seq` with at most
+        /// `max_edits` edits, keeping the best of overlapping matches.
+        ///
+        /// # Examples
+        ///
+        /// ```
+        /// use myfq::primers::*;
+        /// let p = Primer::new("primer", b"GATACAGGTT", b"", Direction::Forward);
+        /// let seq = b"GATACAGGTTCCCCCCCCCCGATCAGGTTCCCC";
+        /// let hits = p.find_all_with_edits(seq, 1);
+        /// assert_eq!(hits.iter().map(|h| h.start).collect::<Vec<_>>(), [0, 20]);
+        /// ```
+        pub fn find_all_with_edits(&self, seq: &[u8], max_edits: usize) -> Vec<PrimerHit> {
+            all_edit_matches(&self.sequence, seq, max_edits)
+        }
+
+        /// Returns every match of the reverse complement of the primer sequence
+        /// in `seq` with at most `max_edits` edits.
+        pub fn find_all_rc_with_edits(&self, seq: &[u8], max_edits: usize) -> Vec<PrimerHit> {
+            all_edit_matches(&self.sequence_rc, seq, max_edits)
+        }
+
+        /// Search in `seq` for the primer sequence, using the base qualities in
+        /// `qual` to weight mismatches as described for [QualityMatch].
+        ///
+        /// # Examples
+        ///
+        /// ```
+        /// use myfq::primers::*;
+        /// let p = Primer::new("primer", b"GATACA", b"GAT", Direction::Forward);
+        /// let qm = QualityMatch { max_penalty: 0.5, min_quality: 0 };
+        ///
+        /// // mismatch at a Q2 base is tolerated, the same mismatch at Q40 is not
+        /// let seq = b"CCCGATTCACCC";
+        /// assert_eq!(p.find_in_with_quality(seq, b"IIIIII#IIIII", &qm).len(), 1);
+        /// assert!(p.find_in_with_quality(seq, b"IIIIIIIIIIII", &qm).is_empty());
+        /// ```
+        pub fn find_in_with_quality(
+            &self,
+            seq: &[u8],
+            qual: &[u8],
+            params: &QualityMatch,
+        ) -> Vec<PrimerHit> {
+            params.find_all(&self.sequence, seq, qual)
+        }
+
+        /// Search in `seq` for the reverse complement of the primer sequence,
+        /// using the base qualities in `qual` to weight mismatches.
+        pub fn find_in_rc_with_quality(
+            &self,
+            seq: &[u8],
+            qual: &[u8],
+            params: &QualityMatch,
+        ) -> Vec<PrimerHit> {
+            params.find_all(&self.sequence_rc, seq, qual)
         }
 
         /// Returns the primer label.
@@ -439,6 +1068,27 @@ pub mod primers {
         pub fn direction(&self) -> Direction {
             self.direction
         }
+
+        /// Returns the primer sequence.
+        pub fn sequence(&self) -> &[u8] {
+            &self.sequence
+        }
+
+        /// Returns the reverse complement of the primer sequence.
+        pub fn sequence_rc(&self) -> &[u8] {
+            &self.sequence_rc
+        }
+
+        /// Returns the index of this sequence within its primer pool; 0 for
+        /// primers which are not pooled.
+        pub fn variant(&self) -> usize {
+            self.variant
+        }
+
+        /// Returns the primer's barcode sequence.
+        pub fn barcode(&self) -> &[u8] {
+            &self.barcode
+        }
     }
 
     #[cfg(test)]
@@ -506,6 +1156,100 @@ pub mod primers {
             assert!(!p.is_found_in(sequence));
         }
 
+        #[test]
+        fn invalid_spacer_range() {
+            let p = Primer::new("primer", b"GATACA", b"GAT", Direction::Forward).with_spacer(5, 2);
+            assert!(p.check().is_err());
+        }
+
+        #[test]
+        fn read_spacer_column() {
+            let text = "p1\tGATACA\tGAT\tF\t0-7\np2\tCCCAAG\tCCC\tR\n";
+            let table = read_primer_table(&mut text.as_bytes()).unwrap();
+            assert_eq!(table[0].spacer(), Some((0, 7)));
+            assert_eq!(table[1].spacer(), None);
+        }
+
+        #[test]
+        fn read_primer_pool() {
+            let text = "p1\tGATACA\tGAT\tF\np1\tGATTCA\tGAT\tF\np2\tCCCAAG\tCCC\tR\n";
+            let table = read_primer_table(&mut text.as_bytes()).unwrap();
+            let variants: Vec<_> = table.iter().map(|p| (p.label(), p.variant())).collect();
+            assert_eq!(variants, [("p1", 0), ("p1", 1), ("p2", 0)]);
+        }
+
+        #[test]
+        fn primer_pool_mixed_directions() {
+            let text = "p1\tGATACA\tGAT\tF\np1\tGATTCA\tGAT\tR\n";
+            assert!(read_primer_table(&mut text.as_bytes()).is_err());
+        }
+
+        #[test]
+        fn exact_hit_positions() {
+            let p = Primer::new("primer", b"GATACA", b"GAT", Direction::Forward);
+            let sequence = b"GATACAACTGTGTATCGATACA";
+            let hits = p.find_in(sequence);
+            assert_eq!(hits.len(), 2);
+            assert_eq!((hits[0].start, hits[0].end), (0, 6));
+            assert_eq!(hits[1].start, 16);
+            let hits_rc = p.find_in_rc(sequence);
+            assert_eq!(hits_rc.len(), 1);
+            assert_eq!(hits_rc[0].start, 10);
+        }
+
+        #[test]
+        fn find_rc_near_end_with_deletion() {
+            let p = Primer::new("p", b"TTGGCCAAGT", b"", Direction::Reverse);
+            // reverse complement ACTTGGCCAA, with the G deleted
+            let seq = [b"TTGGCCAAGT".as_slice(), &[b'A'; 300], b"ACTTGCCAACC"].concat();
+            let hit = p.find_rc_near_end(&seq, 50, 1).unwrap();
+            assert_eq!((hit.start, hit.end, hit.penalty), (310, 319, 1.0));
+            assert_eq!(p.find_rc_near_end(&seq, 50, 0), None);
+            assert_eq!(p.find_near_start(&seq, 50, 0).unwrap().start, 0);
+        }
+
+        #[test]
+        fn quality_match_low_quality_wildcard() {
+            let p = Primer::new("primer", b"GATACA", b"GAT", Direction::Forward);
+            let qm = QualityMatch {
+                max_penalty: 0.0,
+                min_quality: 20,
+            };
+            let seq = b"ACTGGATTCAACTG";
+            let good = b"IIIIIIIIIIIIII";
+            let bad = b"IIIIIII+IIIIII"; // Q10 at the mismatched base
+            assert!(p.find_in_with_quality(seq, good, &qm).is_empty());
+            let hits = p.find_in_with_quality(seq, bad, &qm);
+            assert_eq!(hits.len(), 1);
+            assert_eq!((hits[0].start, hits[0].end), (4, 10));
+        }
+
+        #[test]
+        fn quality_match_rc() {
+            let p = Primer::new("primer", b"GATACA", b"GAT", Direction::Forward);
+            let qm = QualityMatch {
+                max_penalty: 1.0,
+                min_quality: 0,
+            };
+            let seq = b"ACTGTGTATCACTG";
+            let qual = b"IIIIIIIIIIIIII";
+            let hits = p.find_in_rc_with_quality(seq, qual, &qm);
+            assert_eq!(hits.len(), 1);
+            assert_eq!(hits[0].start, 4);
+            assert_eq!(hits[0].penalty, 0.0);
+        }
+
+        #[test]
+        fn quality_match_collapses_overlaps() {
+            let qm = QualityMatch {
+                max_penalty: 1.0,
+                min_quality: 0,
+            };
+            let hits = qm.find_all(b"AAAA", b"AAAAA", b"IIIII");
+            assert_eq!(hits.len(), 1);
+            assert_eq!(hits[0].start, 0);
+        }
+
         #[test]
         fn retrieve_primer_labels() {
             let seq = b"GATACA";
@@ -523,3 +1267,3505 @@ pub mod primers {
         }
     }
 }
+
+pub mod assign {
+    use crate::primers::{Direction, Primer, PrimerHit};
+    use bio::alphabets::dna;
+    use std::fmt;
+
+    /// Orientation of a read relative to the amplicon, written as `+` or `-`.
+    #[derive(Debug, PartialEq, Eq, Copy, Clone)]
+    pub enum Orientation {
+        Forward,
+        Reverse,
+    }
+
+    impl fmt::Display for Orientation {
+        fn fmt(&self, dest: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Orientation::Forward => write!(dest, "+"),
+                Orientation::Reverse => write!(dest, "-"),
+            }
+        }
+    }
+
+    /// A primer found in a read, either as its own sequence or as its reverse complement.
+    #[derive(Debug, Clone, Copy)]
+    pub struct Candidate<'a> {
+        pub primer: &'a Primer,
+        pub hit: PrimerHit,
+        pub rc: bool,
+    }
+
+    impl<'a> Candidate<'a> {
+        /// The label of the primer as found: `label` or `label_rc`.
+        pub fn hit_label(&self) -> &'a str {
+            if self.rc {
+                self.primer.label_rc()
+            } else {
+                self.primer.label()
+            }
+        }
+
+        /// Orientation of the read implied by this hit: a forward primer found
+        /// as itself, or a reverse primer found as its reverse complement, means
+        /// the read is in the forward amplicon orientation.
+        pub fn orientation(&self) -> Orientation {
+            match (self.primer.direction(), self.rc) {
+                (Direction::Forward, false) | (Direction::Reverse, true) => Orientation::Forward,
+                _ => Orientation::Reverse,
+            }
+        }
+
+        /// Distance of the hit from the end of the read at which the primer is
+        /// expected: the 5' end for the primer, the 3' end for its reverse complement.
+        pub fn offset(&self, read_len: usize) -> usize {
+            if self.rc {
+                read_len.saturating_sub(self.hit.end)
+            } else {
+                self.hit.start
+            }
+        }
+
+        /// Whether the hit is at a position allowed by the primer's spacer.
+        ///
+        /// Only hits of the primer as itself, at the 5' end of the read, are
+        /// constrained: the 3' end may be followed by adapter or not be reached.
+        pub fn spacer_allowed(&self) -> bool {
+            self.rc || self.primer.spacer_allows(self.hit.start)
+        }
+
+        /// The observed spacer length: the start of a hit of a primer with a
+        /// spacer, found as itself.
+        pub fn spacer(&self) -> Option<usize> {
+            match (self.rc, self.primer.spacer()) {
+                (false, Some(_)) => Some(self.hit.start),
+                _ => None,
+            }
+        }
+
+        /// Whether the primer's barcode is found exactly within the hit, or
+        /// immediately 5' of it.  Primers without a barcode always agree.
+        pub fn barcode_agrees(&self, seq: &[u8]) -> bool {
+            let barcode = self.primer.barcode();
+            if barcode.is_empty() {
+                return true;
+            }
+            let (window, barcode) = if self.rc {
+                let end = (self.hit.end + barcode.len()).min(seq.len());
+                (&seq[self.hit.start..end], dna::revcomp(barcode))
+            } else {
+                let start = self.hit.start.saturating_sub(barcode.len());
+                (&seq[start..self.hit.end], barcode.to_vec())
+            };
+            window
+                .windows(barcode.len())
+                .any(|w| w == barcode.as_slice())
+        }
+    }
+
+    /// Weights used to score candidates and the margin required to accept the best one.
+    ///
+    /// The score of a candidate is its mismatch penalty, plus `position_weight`
+    /// per base of [Candidate::offset], plus `barcode_weight` if the barcode does
+    /// not agree.  Lower scores are better.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct Resolver {
+        pub margin: f64,
+        pub position_weight: f64,
+        pub barcode_weight: f64,
+    }
+
+    impl Default for Resolver {
+        fn default() -> Self {
+            Self {
+                margin: 0.5,
+                position_weight: 0.01,
+                barcode_weight: 1.0,
+            }
+        }
+    }
+
+    /// Outcome of resolving the candidates for one primer direction.
+    #[derive(Debug)]
+    pub enum Resolution<'a> {
+        /// No primer was found.
+        None,
+        /// A single primer was found, or the best one beat the runner-up by the margin.
+        Unique(Candidate<'a>),
+        /// The best two candidates were within the margin of each other.
+        Ambiguous(Candidate<'a>, Candidate<'a>),
+    }
+
+    impl Resolver {
+        /// Score a candidate found in `seq`.
+        pub fn score(&self, candidate: &Candidate, seq: &[u8]) -> f64 {
+            let mut score =
+                candidate.hit.penalty + self.position_weight * candidate.offset(seq.len()) as f64;
+            if !candidate.barcode_agrees(seq) {
+                score += self.barcode_weight;
+            }
+            score
+        }
+
+        /// Choose among candidates for the same primer direction.
+        ///
+        /// Only the best scoring hit is kept for each primer label, so several
+        /// hits of one primer never make a read ambiguous.  Hits outside the
+        /// primer's spacer range are ignored.
+        ///
+        /// # Examples
+        ///
+        /// ```
+        /// use myfq::assign::*;
+        /// use myfq::primers::*;
+        /// let p1 = Primer::new("p1", b"GATACA", b"", Direction::Forward);
+        /// let p2 = Primer::new("p2", b"ATACAG", b"", Direction::Forward);
+        /// let seq = b"GATACAGTTTTTTTTTTTTTTTTTTTTTTTTTT";
+        /// let candidates = vec![
+        ///     Candidate { primer: &p1, hit: p1.find_in(seq)[0], rc: false },
+        ///     Candidate { primer: &p2, hit: p2.find_in(seq)[0], rc: false },
+        /// ];
+        /// let resolver = Resolver { margin: 0.005, ..Default::default() };
+        /// match resolver.resolve(&candidates, seq) {
+        ///     Resolution::Unique(c) => assert_eq!(c.primer.label(), "p1"),
+        ///     _ => panic!("expected a unique primer"),
+        /// }
+        /// ```
+        pub fn resolve<'a>(&self, candidates: &[Candidate<'a>], seq: &[u8]) -> Resolution<'a> {
+            let mut best: Vec<(f64, Candidate<'a>)> = Vec::new();
+            for &c in candidates.iter().filter(|c| c.spacer_allowed()) {
+                let score = self.score(&c, seq);
+                match best
+                    .iter_mut()
+                    .find(|(_, b)| b.primer.label() == c.primer.label())
+                {
+                    Some(entry) if score < entry.0 => *entry = (score, c),
+                    Some(_) => {}
+                    None => best.push((score, c)),
+                }
+            }
+            best.sort_by(|a, b| a.0.total_cmp(&b.0));
+            match best.as_slice() {
+                [] => Resolution::None,
+                [(_, only)] => Resolution::Unique(*only),
+                [(s1, first), (s2, second), ..] => {
+                    if s2 - s1 >= self.margin {
+                        Resolution::Unique(*first)
+                    } else {
+                        Resolution::Ambiguous(*first, *second)
+                    }
+                }
+            }
+        }
+    }
+
+    /// The part of a read of length `read_len` left after clipping primers:
+    /// everything up to the end of a primer found as itself (including any
+    /// spacer), and everything from the start of a primer found as its
+    /// reverse complement, is removed.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use myfq::assign::*;
+    /// use myfq::primers::*;
+    /// let f = Primer::new("f", b"GATACA", b"", Direction::Forward);
+    /// let r = Primer::new("r", b"CCCAAG", b"", Direction::Reverse);
+    /// let seq = b"TTGATACAGGGGGGCTTGGGTT";
+    /// let fc = Candidate { primer: &f, hit: f.find_in(seq)[0], rc: false };
+    /// let rc = Candidate { primer: &r, hit: r.find_in_rc(seq)[0], rc: true };
+    /// assert_eq!(clip_range(seq.len(), &[fc, rc]), 8..14);
+    /// ```
+    pub fn clip_range(read_len: usize, primers: &[Candidate]) -> std::ops::Range<usize> {
+        let start = primers
+            .iter()
+            .filter(|c| !c.rc)
+            .map(|c| c.hit.end)
+            .max()
+            .unwrap_or(0);
+        let end = primers
+            .iter()
+            .filter(|c| c.rc)
+            .map(|c| c.hit.start)
+            .min()
+            .unwrap_or(read_len);
+        start.min(end)..end.max(start)
+    }
+
+    /// Number of bases between the primer found as itself & the primer found
+    /// as its reverse complement; 0 if they overlap or either is missing.
+    ///
+    /// # Examples
+    /// ```
+    /// use myfq::assign::*;
+    /// use myfq::primers::*;
+    /// let f = Primer::new("f", b"GATACA", b"", Direction::Forward);
+    /// let r = Primer::new("r", b"CCCAAG", b"", Direction::Reverse);
+    /// let seq = b"GATACAGGCTTGGG";
+    /// let fc = Candidate { primer: &f, hit: f.find_in(seq)[0], rc: false };
+    /// let rc = Candidate { primer: &r, hit: r.find_in_rc(seq)[0], rc: true };
+    /// assert_eq!(insert_length(&[fc, rc]), 2);
+    /// ```
+    pub fn insert_length(primers: &[Candidate]) -> usize {
+        let five = primers.iter().find(|c| !c.rc);
+        let three = primers.iter().find(|c| c.rc);
+        match (five, three) {
+            (Some(five), Some(three)) => three.hit.start.saturating_sub(five.hit.end),
+            _ => 0,
+        }
+    }
+
+    /// Positions at which to split a read of amplicons joined end to end.
+    ///
+    /// An amplicon ends with a primer found as its reverse complement; where
+    /// one is followed by a primer found as itself, the read is cut half way
+    /// between the two, leaving any barcodes with their primers.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use myfq::assign::*;
+    /// use myfq::primers::*;
+    /// let f = Primer::new("f", b"GATACA", b"", Direction::Forward);
+    /// let r = Primer::new("r", b"CCCAAG", b"", Direction::Reverse);
+    /// let seq = b"GATACAGGGCTTGGGTTGATACATTTCTTGGG";
+    /// let mut candidates = Vec::new();
+    /// for (p, rc) in [(&f, false), (&r, true)] {
+    ///     let hits = if rc { p.find_in_rc(seq) } else { p.find_in(seq) };
+    ///     candidates.extend(hits.into_iter().map(|hit| Candidate { primer: p, hit, rc }));
+    /// }
+    /// assert_eq!(split_points(&candidates), [16]);
+    /// ```
+    pub fn split_points(candidates: &[Candidate]) -> Vec<usize> {
+        let mut hits: Vec<&Candidate> = candidates.iter().collect();
+        hits.sort_by_key(|c| (c.hit.start, c.hit.end));
+        hits.windows(2)
+            .filter(|w| w[0].rc && !w[1].rc && w[1].hit.start >= w[0].hit.end)
+            .map(|w| (w[0].hit.end + w[1].hit.start) / 2)
+            .collect()
+    }
+
+    /// Reasons for suspecting that a read is a chimera or concatemer.
+    #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone)]
+    pub enum Chimera {
+        /// A forward primer occurs at more than one place in the read.
+        RepeatedForward,
+        /// A primer was found away from both ends of the read.
+        InternalPrimer,
+        /// Forward and reverse primers were only found in the same orientation.
+        InconsistentOrientation,
+    }
+
+    impl fmt::Display for Chimera {
+        fn fmt(&self, dest: &mut fmt::Formatter) -> fmt::Result {
+            let s = match self {
+                Chimera::RepeatedForward => "repeated",
+                Chimera::InternalPrimer => "internal",
+                Chimera::InconsistentOrientation => "orientation",
+            };
+            write!(dest, "{s}")
+        }
+    }
+
+    /// Examine the layout of the primer hits in a read of length `read_len`.
+    ///
+    /// Primer hits starting and ending more than `end_window` bases from the
+    /// ends of the read are internal.  Overlapping forward hits, e.g. from
+    /// similar primers matching at the same place, count as one occurrence.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use myfq::assign::*;
+    /// use myfq::primers::*;
+    /// let f = Primer::new("f", b"GATACA", b"", Direction::Forward);
+    /// let seq = b"GATACATTTTTTTTTTTTTTTGATACATTTTTTTTTTTTTTT";
+    /// let forward: Vec<Candidate> = f
+    ///     .find_in(seq)
+    ///     .into_iter()
+    ///     .map(|hit| Candidate { primer: &f, hit, rc: false })
+    ///     .collect();
+    /// assert_eq!(
+    ///     detect_chimera(&forward, &[], seq.len(), 10),
+    ///     Some(Chimera::RepeatedForward)
+    /// );
+    /// ```
+    pub fn detect_chimera(
+        forward: &[Candidate],
+        reverse: &[Candidate],
+        read_len: usize,
+        end_window: usize,
+    ) -> Option<Chimera> {
+        let mut loci: Vec<(usize, usize)> =
+            forward.iter().map(|c| (c.hit.start, c.hit.end)).collect();
+        loci.sort();
+        let occurrences = loci
+            .iter()
+            .fold((0, 0), |(n, last_end), &(start, end)| {
+                if n == 0 || start >= last_end {
+                    (n + 1, end)
+                } else {
+                    (n, last_end.max(end))
+                }
+            })
+            .0;
+        if occurrences > 1 {
+            return Some(Chimera::RepeatedForward);
+        }
+        if forward
+            .iter()
+            .chain(reverse)
+            .any(|c| c.hit.start > end_window && read_len.saturating_sub(c.hit.end) > end_window)
+        {
+            return Some(Chimera::InternalPrimer);
+        }
+        if !forward.is_empty()
+            && !reverse.is_empty()
+            && forward.iter().all(|f| reverse.iter().all(|r| f.rc == r.rc))
+        {
+            return Some(Chimera::InconsistentOrientation);
+        }
+        None
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn exact<'a>(primer: &'a Primer, seq: &[u8], rc: bool) -> Vec<Candidate<'a>> {
+            let hits = if rc {
+                primer.find_in_rc(seq)
+            } else {
+                primer.find_in(seq)
+            };
+            hits.into_iter()
+                .map(|hit| Candidate { primer, hit, rc })
+                .collect()
+        }
+
+        #[test]
+        fn no_candidates() {
+            let r = Resolver::default();
+            assert!(matches!(r.resolve(&[], b"ACGT"), Resolution::None));
+        }
+
+        #[test]
+        fn equal_candidates_are_ambiguous() {
+            let p1 = Primer::new("p1", b"GATACA", b"", Direction::Forward);
+            let p2 = Primer::new("p2", b"CCCGGG", b"", Direction::Forward);
+            let seq = b"GATACATTTTCCCGGG";
+            let mut candidates = exact(&p1, seq, false);
+            candidates.extend(exact(&p2, seq, true));
+            // p2 is found as its own reverse complement at the 3' end
+            let r = Resolver::default();
+            match r.resolve(&candidates, seq) {
+                Resolution::Ambiguous(a, b) => {
+                    assert_eq!(a.offset(seq.len()), 0);
+                    assert_eq!(b.offset(seq.len()), 0);
+                }
+                other => panic!("expected ambiguous, got {:?}", other),
+            }
+        }
+
+        #[test]
+        fn barcode_breaks_tie() {
+            let p1 = Primer::new("p1", b"GATACA", b"AAC", Direction::Forward);
+            let p2 = Primer::new("p2", b"GATACA", b"TTG", Direction::Forward);
+            let seq = b"AACGATACATTTTTTTT";
+            let mut candidates = exact(&p1, seq, false);
+            candidates.extend(exact(&p2, seq, false));
+            let r = Resolver::default();
+            match r.resolve(&candidates, seq) {
+                Resolution::Unique(c) => assert_eq!(c.primer.label(), "p1"),
+                other => panic!("expected unique, got {:?}", other),
+            }
+        }
+
+        #[test]
+        fn well_formed_read_is_not_chimeric() {
+            let f = Primer::new("f", b"GATACA", b"", Direction::Forward);
+            let r = Primer::new("r", b"CCCAAG", b"", Direction::Reverse);
+            let seq = b"GATACATTTTTTTTTTTTTTTTTTTTTTTTCTTGGG";
+            let forward = exact(&f, seq, false);
+            let reverse = exact(&r, seq, true);
+            assert_eq!(detect_chimera(&forward, &reverse, seq.len(), 5), None);
+        }
+
+        #[test]
+        fn internal_primer_is_chimeric() {
+            let f = Primer::new("f", b"GATACA", b"", Direction::Forward);
+            let r = Primer::new("r", b"CCCAAG", b"", Direction::Reverse);
+            let seq = b"GATACATTTTTTTCTTGGGTTTTTTTTTTTTTTTTTTTT";
+            let forward = exact(&f, seq, false);
+            let reverse = exact(&r, seq, true);
+            assert_eq!(
+                detect_chimera(&forward, &reverse, seq.len(), 5),
+                Some(Chimera::InternalPrimer)
+            );
+        }
+
+        #[test]
+        fn same_orientation_is_chimeric() {
+            let f = Primer::new("f", b"GATACA", b"", Direction::Forward);
+            let r = Primer::new("r", b"CCCAAG", b"", Direction::Reverse);
+            let seq = b"GATACATTTTTTTTTTTTTTTTTTTTTTTTCCCAAG";
+            let forward = exact(&f, seq, false);
+            let reverse = exact(&r, seq, false);
+            assert_eq!(
+                detect_chimera(&forward, &reverse, seq.len(), 5),
+                Some(Chimera::InconsistentOrientation)
+            );
+        }
+
+        #[test]
+        fn candidate_orientation() {
+            let f = Primer::new("f", b"GATACA", b"", Direction::Forward);
+            let r = Primer::new("r", b"CCCAAG", b"", Direction::Reverse);
+            let seq = b"GATACATTTTTTTTTTTTTTTTTTTTTTTTCTTGGG";
+            assert_eq!(exact(&f, seq, false)[0].orientation(), Orientation::Forward);
+            assert_eq!(exact(&r, seq, true)[0].orientation(), Orientation::Forward);
+            let seq = b"CCCAAGTTTTTTTTTTTTTTTTTTTTTTTTTGTATC";
+            assert_eq!(exact(&f, seq, true)[0].orientation(), Orientation::Reverse);
+            assert_eq!(exact(&r, seq, false)[0].orientation(), Orientation::Reverse);
+        }
+
+        #[test]
+        fn pool_variants_are_one_primer() {
+            let v0 = Primer::new("p1", b"GATACA", b"", Direction::Forward);
+            let v1 = Primer::new("p1", b"ATACAG", b"", Direction::Forward);
+            let seq = b"GATACAGTTTTTTTTTTTT";
+            let mut candidates = exact(&v0, seq, false);
+            candidates.extend(exact(&v1, seq, false));
+            match Resolver::default().resolve(&candidates, seq) {
+                Resolution::Unique(c) => assert_eq!(c.hit.start, 0),
+                other => panic!("expected unique, got {:?}", other),
+            }
+        }
+
+        #[test]
+        fn spacer_range_limits_hits() {
+            let p1 = Primer::new("p1", b"GATACA", b"", Direction::Forward).with_spacer(1, 3);
+            let r = Resolver::default();
+            let seq = b"AAGATACATTTTTTTTTTTT";
+            match r.resolve(&exact(&p1, seq, false), seq) {
+                Resolution::Unique(c) => assert_eq!(c.spacer(), Some(2)),
+                other => panic!("expected unique, got {:?}", other),
+            }
+            let seq = b"AAAAAGATACATTTTTTTTTTTT";
+            assert!(matches!(
+                r.resolve(&exact(&p1, seq, false), seq),
+                Resolution::None
+            ));
+        }
+
+        #[test]
+        fn repeated_primer_is_not_ambiguous() {
+            let p1 = Primer::new("p1", b"GATACA", b"", Direction::Forward);
+            let seq = b"GATACATTTTGATACA";
+            let r = Resolver::default();
+            match r.resolve(&exact(&p1, seq, false), seq) {
+                Resolution::Unique(c) => assert_eq!(c.hit.start, 0),
+                other => panic!("expected unique, got {:?}", other),
+            }
+        }
+    }
+}
+
+pub mod annotate {
+    use crate::amplicon::AmpliconMatch;
+    use crate::assign::{Candidate, Orientation};
+    use crate::samples::PrimerPair;
+    use itertools::Itertools;
+    use serde::{Deserialize, Serialize};
+    use std::str::FromStr;
+
+    /// A `key=value` field written to the FASTQ description of an output read.
+    #[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Field {
+        Sample,
+        Primers,
+        Orient,
+        Hits,
+        Segments,
+        Spacer,
+        Target,
+        Amplicon,
+        Umi,
+        Strand,
+    }
+
+    impl Field {
+        pub const ALL: [Field; 10] = [
+            Field::Sample,
+            Field::Target,
+            Field::Primers,
+            Field::Orient,
+            Field::Hits,
+            Field::Segments,
+            Field::Spacer,
+            Field::Amplicon,
+            Field::Umi,
+            Field::Strand,
+        ];
+
+        pub fn key(&self) -> &'static str {
+            match self {
+                Field::Sample => "sample",
+                Field::Primers => "primers",
+                Field::Orient => "orient",
+                Field::Hits => "hits",
+                Field::Segments => "segments",
+                Field::Spacer => "spacer",
+                Field::Target => "target",
+                Field::Amplicon => "amplicon",
+                Field::Umi => "umi",
+                Field::Strand => "strand",
+            }
+        }
+    }
+
+    impl FromStr for Field {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            Field::ALL
+                .into_iter()
+                .find(|f| f.key() == s)
+                .ok_or_else(|| format!("unknown description field '{s}'"))
+        }
+    }
+
+    /// The evidence for assigning a read, written to its FASTQ description.
+    ///
+    /// `primers` holds `forward-reverse` for an assigned read, `dimer:forward-reverse`
+    /// for a primer dimer, or the reason it was not assigned; `pair` is only set
+    /// for assigned reads & primer dimers.  Positions in `hits` & `segments`
+    /// are in the read as sequenced, before any clipping or reorientation.
+    /// Missing values are written as `.`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use myfq::annotate::*;
+    /// use myfq::assign::Orientation;
+    /// let a = Annotation {
+    ///     sample: Some("S1".to_string()),
+    ///     target: None,
+    ///     primers: "oVK001-oVK010".to_string(),
+    ///     pair: None,
+    ///     orient: Some(Orientation::Forward),
+    ///     hits: vec![("oVK001".to_string(), 0, 20), ("oVK010rc".to_string(), 230, 250)],
+    ///     segments: Vec::new(),
+    ///     spacer: Some(3),
+    ///     amplicon: None,
+    ///     umi: None,
+    ///     strand: None,
+    /// };
+    /// assert_eq!(
+    ///     a.describe(&Field::ALL),
+    ///     "sample=S1 target=. primers=oVK001-oVK010 orient=+ hits=oVK001:0:20,oVK010rc:230:250 segments=. spacer=3 amplicon=. umi=. strand=."
+    /// );
+    /// assert_eq!(a.describe(&[Field::Primers]), "primers=oVK001-oVK010");
+    /// ```
+    #[derive(Debug, Default, PartialEq)]
+    pub struct Annotation {
+        pub sample: Option<String>,
+        pub target: Option<String>,
+        pub primers: String,
+        pub pair: Option<PrimerPair>,
+        pub orient: Option<Orientation>,
+        pub hits: Vec<(String, usize, usize)>,
+        pub segments: Vec<(String, usize, usize)>,
+        pub spacer: Option<usize>,
+        pub amplicon: Option<AmpliconMatch>,
+        pub umi: Option<String>,
+        /// `-` if the read was reverse complemented on output, `+` if it was
+        /// written as sequenced; only set when reads are reoriented.
+        pub strand: Option<Orientation>,
+    }
+
+    fn format_ranges(ranges: &[(String, usize, usize)]) -> String {
+        if ranges.is_empty() {
+            return ".".to_string();
+        }
+        ranges
+            .iter()
+            .map(|(label, start, end)| format!("{label}:{start}:{end}"))
+            .join(",")
+    }
+
+    fn parse_ranges(value: &str) -> Vec<(String, usize, usize)> {
+        value
+            .split(',')
+            .filter_map(|h| {
+                let mut parts = h.rsplitn(3, ':');
+                let end = parts.next()?.parse().ok()?;
+                let start = parts.next()?.parse().ok()?;
+                Some((parts.next()?.to_string(), start, end))
+            })
+            .collect()
+    }
+
+    fn parse_orientation(value: &str) -> Option<Orientation> {
+        match value {
+            "+" => Some(Orientation::Forward),
+            "-" => Some(Orientation::Reverse),
+            _ => None,
+        }
+    }
+
+    impl Annotation {
+        /// Whether the read as written is in reverse primer orientation: its
+        /// orientation is `-` & it was not reverse complemented on output.
+        pub fn written_reversed(&self) -> bool {
+            self.orient == Some(Orientation::Reverse) && self.strand != Some(Orientation::Reverse)
+        }
+
+        /// Record every primer hit from `candidates` as `label:start:end`,
+        /// in order of position in the read.
+        pub fn add_hits(&mut self, candidates: &[Candidate]) {
+            self.hits.extend(
+                candidates
+                    .iter()
+                    .map(|c| (c.hit_label().to_string(), c.hit.start, c.hit.end)),
+            );
+            self.hits.sort_by_key(|(_, start, end)| (*start, *end));
+        }
+
+        /// Parse a FASTQ description written by [Annotation::describe].
+        ///
+        /// Unknown keys are ignored.  The primer pair is recovered from a
+        /// `primers` value of the form `forward-reverse`.
+        ///
+        /// # Examples
+        ///
+        /// ```
+        /// use myfq::annotate::*;
+        /// let a = Annotation::parse("sample=S1 primers=oVK001-oVK010 orient=- hits=.");
+        /// assert_eq!(a.sample.as_deref(), Some("S1"));
+        /// assert_eq!(a.pair.unwrap().reverse, "oVK010");
+        /// assert!(a.hits.is_empty());
+        /// ```
+        pub fn parse(description: &str) -> Annotation {
+            let mut a = Annotation::default();
+            for (key, value) in description
+                .split_ascii_whitespace()
+                .filter_map(|kv| kv.split_once('='))
+            {
+                match key.parse::<Field>() {
+                    Ok(Field::Sample) if value != "." => a.sample = Some(value.to_string()),
+                    Ok(Field::Target) if value != "." => a.target = Some(value.to_string()),
+                    Ok(Field::Primers) => a.primers = value.to_string(),
+                    Ok(Field::Orient) => a.orient = parse_orientation(value),
+                    Ok(Field::Strand) => a.strand = parse_orientation(value),
+                    Ok(Field::Hits) => a.hits = parse_ranges(value),
+                    Ok(Field::Segments) => a.segments = parse_ranges(value),
+                    Ok(Field::Spacer) => a.spacer = value.parse().ok(),
+                    Ok(Field::Amplicon) => a.amplicon = value.parse().ok(),
+                    Ok(Field::Umi) if value != "." => a.umi = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+            let pair = a.primers.strip_prefix("dimer:").unwrap_or(&a.primers);
+            if !pair.contains(':') && pair != "invalid" {
+                a.pair = pair.split_once('-').map(|(f, r)| PrimerPair {
+                    forward: f.to_string(),
+                    reverse: r.to_string(),
+                });
+            }
+            a
+        }
+
+        /// The class of the read: `assigned`, `dimer`, or the reason it was
+        /// not assigned without any detail, e.g. `chimera`.
+        pub fn classification(&self) -> &str {
+            match self.pair {
+                Some(_) if self.primers.starts_with("dimer:") => "dimer",
+                Some(_) => "assigned",
+                None => self.primers.split(':').next().unwrap_or_default(),
+            }
+        }
+
+        /// Format the requested fields as space separated `key=value` pairs.
+        pub fn describe(&self, fields: &[Field]) -> String {
+            fields
+                .iter()
+                .map(|f| {
+                    let value = match f {
+                        Field::Sample => self.sample.clone().unwrap_or(".".to_string()),
+                        Field::Target => self.target.clone().unwrap_or(".".to_string()),
+                        Field::Primers => self.primers.clone(),
+                        Field::Orient => self.orient.map_or(".".to_string(), |o| o.to_string()),
+                        Field::Hits => format_ranges(&self.hits),
+                        Field::Segments => format_ranges(&self.segments),
+                        Field::Spacer => self.spacer.map_or(".".to_string(), |n| n.to_string()),
+                        Field::Amplicon => self.amplicon.map_or(".".to_string(), |m| m.to_string()),
+                        Field::Umi => self.umi.clone().unwrap_or(".".to_string()),
+                        Field::Strand => self.strand.map_or(".".to_string(), |o| o.to_string()),
+                    };
+                    format!("{}={}", f.key(), value)
+                })
+                .join(" ")
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn parse_fields() {
+            assert_eq!("orient".parse::<Field>(), Ok(Field::Orient));
+            assert!("bogus".parse::<Field>().is_err());
+        }
+
+        #[test]
+        fn parse_round_trip() {
+            let a = Annotation {
+                sample: Some("S1".to_string()),
+                target: Some("amp1".to_string()),
+                primers: "oVK001-oVK010".to_string(),
+                pair: Some(PrimerPair {
+                    forward: "oVK001".to_string(),
+                    reverse: "oVK010".to_string(),
+                }),
+                orient: Some(Orientation::Forward),
+                hits: vec![
+                    ("oVK001".to_string(), 0, 20),
+                    ("oVK010rc".to_string(), 230, 250),
+                ],
+                segments: vec![("insert".to_string(), 20, 230)],
+                spacer: Some(0),
+                amplicon: Some(AmpliconMatch {
+                    insert: 210,
+                    identity: 0.5,
+                    on_target: false,
+                }),
+                umi: Some("ACGTACGT".to_string()),
+                strand: Some(Orientation::Reverse),
+            };
+            assert_eq!(Annotation::parse(&a.describe(&Field::ALL)), a);
+        }
+
+        #[test]
+        fn parse_unassigned() {
+            let a = Annotation::parse("sample=. primers=chimera:internal orient=. hits=.");
+            assert_eq!(a.pair, None);
+            assert_eq!(a.classification(), "chimera");
+        }
+
+        #[test]
+        fn parse_dimer() {
+            let a = Annotation::parse("sample=S1 primers=dimer:oVK001-oVK010");
+            assert_eq!(a.pair.as_ref().unwrap().forward, "oVK001");
+            assert_eq!(a.classification(), "dimer");
+        }
+
+        #[test]
+        fn reoriented_reads() {
+            assert!(Annotation::parse("orient=-").written_reversed());
+            assert!(!Annotation::parse("orient=- strand=-").written_reversed());
+            assert!(!Annotation::parse("orient=+ strand=+").written_reversed());
+        }
+
+        #[test]
+        fn missing_values() {
+            let a = Annotation {
+                primers: "invalid".to_string(),
+                ..Default::default()
+            };
+            assert_eq!(
+                a.describe(&Field::ALL),
+                "sample=. target=. primers=invalid orient=. hits=. segments=. spacer=. amplicon=. umi=. strand=."
+            );
+        }
+    }
+}
+
+pub mod unaligned {
+    use crate::annotate::{Annotation, Field};
+    use crate::samples::SamplesTable;
+    use noodles::bam;
+    use noodles::sam::{
+        self,
+        alignment::{
+            io::Write as AlignmentWrite,
+            record::{data::field::Tag, Flags},
+            record_buf::{data::field::Value, Data, QualityScores, Sequence},
+            RecordBuf,
+        },
+        header::record::value::{
+            map::{read_group::tag as rg_tag, ReadGroup},
+            Map,
+        },
+    };
+    use std::collections::BTreeMap;
+    use std::io::{self, Write};
+
+    /// Custom tag holding the forward primer label.
+    pub const FORWARD_PRIMER: Tag = Tag::new(b'p', b'f');
+    /// Custom tag holding the reverse primer label.
+    pub const REVERSE_PRIMER: Tag = Tag::new(b'p', b'r');
+    /// Custom tag holding the read orientation, `+` or `-`.
+    pub const ORIENTATION: Tag = Tag::new(b'o', b'r');
+    /// Custom tag holding the panel target.
+    pub const TARGET: Tag = Tag::new(b't', b'g');
+
+    /// Build a SAM header with one `@RG` line per sample, with the sample name
+    /// as both `ID` and `SM`, and the sample's primer pairs as `DS`, sorted &
+    /// separated by commas.
+    pub fn header(samples: &SamplesTable) -> io::Result<sam::Header> {
+        let mut pairs = BTreeMap::<&str, Vec<String>>::new();
+        for (primers, sample) in samples.iter() {
+            pairs
+                .entry(sample.name())
+                .or_default()
+                .push(format!("{}-{}", primers.forward, primers.reverse));
+        }
+        let mut builder = sam::Header::builder();
+        for (name, mut primers) in pairs {
+            primers.sort();
+            let map = Map::<ReadGroup>::builder()
+                .insert(rg_tag::SAMPLE, name)
+                .insert(rg_tag::DESCRIPTION, primers.join(","))
+                .build()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            builder = builder.add_read_group(name, map);
+        }
+        Ok(builder.build())
+    }
+
+    /// Build an unmapped record from a FASTQ read and its annotation.
+    ///
+    /// The sample goes in the `RG` tag, the primers, orientation & target in
+    /// the `pf`, `pr`, `or` & `tg` tags, any UMI in `RX`, and the full
+    /// description in `CO`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use myfq::annotate::Annotation;
+    /// use myfq::unaligned::*;
+    /// let a = Annotation { primers: "invalid".to_string(), ..Default::default() };
+    /// let r = record("r1", b"ACGT", b"II#I", &a);
+    /// assert_eq!(r.quality_scores().as_ref(), &[40, 40, 2, 40]);
+    /// ```
+    pub fn record(id: &str, seq: &[u8], qual: &[u8], annotation: &Annotation) -> RecordBuf {
+        let mut data = Data::default();
+        if let Some(sample) = &annotation.sample {
+            data.insert(Tag::READ_GROUP, Value::from(sample.as_str()));
+        }
+        if let Some(pair) = &annotation.pair {
+            data.insert(FORWARD_PRIMER, Value::from(pair.forward.as_str()));
+            data.insert(REVERSE_PRIMER, Value::from(pair.reverse.as_str()));
+        }
+        if let Some(orient) = annotation.orient {
+            data.insert(ORIENTATION, Value::from(orient.to_string()));
+        }
+        if let Some(target) = &annotation.target {
+            data.insert(TARGET, Value::from(target.as_str()));
+        }
+        if let Some(umi) = &annotation.umi {
+            data.insert(Tag::UMI_SEQUENCE, Value::from(umi.as_str()));
+        }
+        data.insert(Tag::COMMENT, Value::from(annotation.describe(&Field::ALL)));
+        RecordBuf::builder()
+            .set_name(id)
+            .set_flags(Flags::UNMAPPED)
+            .set_sequence(Sequence::from(seq))
+            .set_quality_scores(QualityScores::from(
+                qual.iter()
+                    .map(|q| q.saturating_sub(33))
+                    .collect::<Vec<u8>>(),
+            ))
+            .set_data(data)
+            .build()
+    }
+
+    /// Writes unmapped records as SAM or BAM.
+    pub struct UnalignedWriter {
+        header: sam::Header,
+        inner: Box<dyn AlignmentWrite>,
+    }
+
+    impl UnalignedWriter {
+        /// Create a SAM writer and write the header built from `samples`.
+        pub fn sam<W: Write + 'static>(dest: W, samples: &SamplesTable) -> io::Result<Self> {
+            Self::init(Box::new(sam::io::Writer::new(dest)), samples)
+        }
+
+        /// Create a BAM writer and write the header built from `samples`.
+        pub fn bam<W: Write + 'static>(dest: W, samples: &SamplesTable) -> io::Result<Self> {
+            Self::init(Box::new(bam::io::Writer::new(dest)), samples)
+        }
+
+        fn init(mut inner: Box<dyn AlignmentWrite>, samples: &SamplesTable) -> io::Result<Self> {
+            let header = header(samples)?;
+            inner.write_alignment_header(&header)?;
+            Ok(Self { header, inner })
+        }
+
+        pub fn write(
+            &mut self,
+            id: &str,
+            seq: &[u8],
+            qual: &[u8],
+            annotation: &Annotation,
+        ) -> io::Result<()> {
+            let r = record(id, seq, qual, annotation);
+            self.inner.write_alignment_record(&self.header, &r)
+        }
+
+        /// Flush the output; for BAM this writes the end-of-file marker.
+        pub fn finish(&mut self) -> io::Result<()> {
+            self.inner.finish(&self.header)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::assign::Orientation;
+        use crate::samples::{fake_samples_table, read_wide_table, PrimerPair};
+
+        #[test]
+        fn header_has_read_groups() {
+            let samples = fake_samples_table(true).unwrap();
+            let h = header(&samples).unwrap();
+            assert_eq!(h.read_groups().len(), 2);
+            assert!(h.read_groups().contains_key(&b"sample 1"[..]));
+        }
+
+        #[test]
+        fn header_lists_every_pair_of_a_sample() {
+            let table = "\tr1\tr2\nf2\tS1\tS2\nf1\tS1\tS2\n";
+            let samples = read_wide_table(Box::new(table.as_bytes())).unwrap();
+            let h = header(&samples).unwrap();
+            assert_eq!(h.read_groups().len(), 2);
+            let s1 = &h.read_groups()[&b"S1"[..]];
+            assert_eq!(
+                s1.other_fields()
+                    .get(&rg_tag::DESCRIPTION)
+                    .map(|v| v.as_ref()),
+                Some(&b"f1-r1,f2-r1"[..])
+            );
+        }
+
+        #[test]
+        fn record_tags() {
+            let a = Annotation {
+                sample: Some("S1".to_string()),
+                primers: "oVK001-oVK010".to_string(),
+                pair: Some(PrimerPair {
+                    forward: "oVK001".to_string(),
+                    reverse: "oVK010".to_string(),
+                }),
+                orient: Some(Orientation::Reverse),
+                ..Default::default()
+            };
+            let r = record("r1", b"ACGT", b"IIII", &a);
+            assert!(r.flags().is_unmapped());
+            assert_eq!(r.data().get(&Tag::READ_GROUP), Some(&Value::from("S1")));
+            assert_eq!(r.data().get(&FORWARD_PRIMER), Some(&Value::from("oVK001")));
+            assert_eq!(r.data().get(&REVERSE_PRIMER), Some(&Value::from("oVK010")));
+            assert_eq!(r.data().get(&ORIENTATION), Some(&Value::from("-")));
+        }
+    }
+}
+
+pub mod assignments {
+    use crate::annotate::Annotation;
+    use crate::assign::{Candidate, Orientation, Resolution};
+    use std::error::Error;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
+
+    /// Column names of the assignments table, in order.
+    pub const COLUMNS: [&str; 13] = [
+        "read_id",
+        "forward",
+        "reverse",
+        "orient",
+        "forward_start",
+        "forward_end",
+        "reverse_start",
+        "reverse_end",
+        "forward_penalty",
+        "reverse_penalty",
+        "sample",
+        "target",
+        "classification",
+    ];
+
+    /// Where a resolved primer was found in the read.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Placement {
+        pub label: String,
+        pub start: usize,
+        pub end: usize,
+        /// Summed mismatch penalty of the hit, see [crate::primers::PrimerHit].
+        pub penalty: f64,
+    }
+
+    impl From<&Candidate<'_>> for Placement {
+        fn from(c: &Candidate) -> Self {
+            Placement {
+                label: c.primer.label().to_string(),
+                start: c.hit.start,
+                end: c.hit.end,
+                penalty: c.hit.penalty,
+            }
+        }
+    }
+
+    /// One row of the assignments table: why a read went where it did.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Assignment {
+        pub read_id: String,
+        pub forward: Option<Placement>,
+        pub reverse: Option<Placement>,
+        pub orient: Option<Orientation>,
+        pub sample: Option<String>,
+        pub target: Option<String>,
+        pub classification: String,
+    }
+
+    impl Assignment {
+        /// Build the row for a read from its annotation and the resolved primers.
+        ///
+        /// Uniquely resolved primers are recorded even if the read was not
+        /// assigned.  The classification is `assigned`, `dimer`, or the reason
+        /// for not assigning the read taken from the annotation.
+        pub fn new(
+            read_id: &str,
+            annotation: &Annotation,
+            forward: &Resolution,
+            reverse: &Resolution,
+        ) -> Self {
+            let placement = |r: &Resolution| match r {
+                Resolution::Unique(c) => Some(Placement::from(c)),
+                _ => None,
+            };
+            Assignment {
+                read_id: read_id.to_string(),
+                forward: placement(forward),
+                reverse: placement(reverse),
+                orient: annotation.orient,
+                sample: annotation.sample.clone(),
+                target: annotation.target.clone(),
+                classification: match annotation.pair {
+                    Some(_) => annotation.classification().to_string(),
+                    None => annotation.primers.clone(),
+                },
+            }
+        }
+
+        /// Build the row for a read that never reached primer search, with
+        /// `reason` as the classification.
+        pub fn rejected(read_id: &str, reason: &str) -> Self {
+            Assignment {
+                read_id: read_id.to_string(),
+                forward: None,
+                reverse: None,
+                orient: None,
+                sample: None,
+                target: None,
+                classification: reason.to_string(),
+            }
+        }
+
+        /// The row as strings, in the order of [COLUMNS]; missing values are empty.
+        pub fn fields(&self) -> [String; 13] {
+            let opt = |v: Option<String>| v.unwrap_or_default();
+            let f = self.forward.as_ref();
+            let r = self.reverse.as_ref();
+            [
+                self.read_id.clone(),
+                opt(f.map(|p| p.label.clone())),
+                opt(r.map(|p| p.label.clone())),
+                opt(self.orient.map(|o| o.to_string())),
+                opt(f.map(|p| p.start.to_string())),
+                opt(f.map(|p| p.end.to_string())),
+                opt(r.map(|p| p.start.to_string())),
+                opt(r.map(|p| p.end.to_string())),
+                opt(f.map(|p| p.penalty.to_string())),
+                opt(r.map(|p| p.penalty.to_string())),
+                opt(self.sample.clone()),
+                opt(self.target.clone()),
+                self.classification.clone(),
+            ]
+        }
+    }
+
+    /// Destination for assignment rows.
+    pub trait WriteAssignments {
+        fn write(&mut self, assignment: &Assignment) -> Result<(), Box<dyn Error>>;
+        /// Flush any buffered rows; must be called once after the last row.
+        fn finish(&mut self) -> Result<(), Box<dyn Error>>;
+    }
+
+    /// Writes assignments as tab separated text with a header line.
+    pub struct TsvWriter<W: Write> {
+        inner: csv::Writer<W>,
+    }
+
+    impl<W: Write> TsvWriter<W> {
+        pub fn new(dest: W) -> Result<Self, Box<dyn Error>> {
+            let mut inner = csv::WriterBuilder::new().delimiter(b'\t').from_writer(dest);
+            inner.write_record(COLUMNS)?;
+            Ok(Self { inner })
+        }
+    }
+
+    impl<W: Write> WriteAssignments for TsvWriter<W> {
+        fn write(&mut self, assignment: &Assignment) -> Result<(), Box<dyn Error>> {
+            self.inner.write_record(assignment.fields())?;
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+            self.inner.flush()?;
+            Ok(())
+        }
+    }
+
+    /// Create a writer for `path`: Parquet if the name ends in `.parquet`
+    /// (requires the `parquet` feature), TSV otherwise.
+    pub fn create(path: &Path) -> Result<Box<dyn WriteAssignments>, Box<dyn Error>> {
+        if path.extension().is_some_and(|e| e == "parquet") {
+            #[cfg(feature = "parquet")]
+            {
+                let file = File::create(path)?;
+                return Ok(Box::new(parquet_writer::ParquetWriter::new(file)?));
+            }
+            #[cfg(not(feature = "parquet"))]
+            return Err("Parquet output requires the `parquet` feature.".into());
+        }
+        let file = File::create(path)?;
+        Ok(Box::new(TsvWriter::new(std::io::BufWriter::new(file))?))
+    }
+
+    #[cfg(feature = "parquet")]
+    pub mod parquet_writer {
+        use super::{Assignment, WriteAssignments};
+        use arrow_array::{
+            builder::{Float64Builder, StringBuilder, UInt64Builder},
+            ArrayRef, RecordBatch,
+        };
+        use arrow_schema::{DataType, Field, Schema};
+        use parquet::arrow::ArrowWriter;
+        use std::error::Error;
+        use std::fs::File;
+        use std::sync::Arc;
+
+        const BATCH_SIZE: usize = 8192;
+
+        /// Writes assignments to a Parquet file in batches of `BATCH_SIZE` rows.
+        pub struct ParquetWriter {
+            writer: Option<ArrowWriter<File>>,
+            schema: Arc<Schema>,
+            rows: Vec<Assignment>,
+        }
+
+        impl ParquetWriter {
+            pub fn new(file: File) -> Result<Self, Box<dyn Error>> {
+                let text = |name| Field::new(name, DataType::Utf8, true);
+                let position = |name| Field::new(name, DataType::UInt64, true);
+                let penalty = |name| Field::new(name, DataType::Float64, true);
+                let schema = Arc::new(Schema::new(vec![
+                    Field::new("read_id", DataType::Utf8, false),
+                    text("forward"),
+                    text("reverse"),
+                    text("orient"),
+                    position("forward_start"),
+                    position("forward_end"),
+                    position("reverse_start"),
+                    position("reverse_end"),
+                    penalty("forward_penalty"),
+                    penalty("reverse_penalty"),
+                    text("sample"),
+                    text("target"),
+                    Field::new("classification", DataType::Utf8, false),
+                ]));
+                let writer = ArrowWriter::try_new(file, schema.clone(), None)?;
+                Ok(Self {
+                    writer: Some(writer),
+                    schema,
+                    rows: Vec::with_capacity(BATCH_SIZE),
+                })
+            }
+
+            fn flush_rows(&mut self) -> Result<(), Box<dyn Error>> {
+                if self.rows.is_empty() {
+                    return Ok(());
+                }
+                let text = |f: &dyn Fn(&Assignment) -> Option<String>| -> ArrayRef {
+                    let mut b = StringBuilder::new();
+                    self.rows.iter().for_each(|a| b.append_option(f(a)));
+                    Arc::new(b.finish())
+                };
+                let position = |f: &dyn Fn(&Assignment) -> Option<usize>| -> ArrayRef {
+                    let mut b = UInt64Builder::new();
+                    self.rows
+                        .iter()
+                        .for_each(|a| b.append_option(f(a).map(|v| v as u64)));
+                    Arc::new(b.finish())
+                };
+                let penalty = |f: &dyn Fn(&Assignment) -> Option<f64>| -> ArrayRef {
+                    let mut b = Float64Builder::new();
+                    self.rows.iter().for_each(|a| b.append_option(f(a)));
+                    Arc::new(b.finish())
+                };
+                let columns = vec![
+                    text(&|a| Some(a.read_id.clone())),
+                    text(&|a| a.forward.as_ref().map(|p| p.label.clone())),
+                    text(&|a| a.reverse.as_ref().map(|p| p.label.clone())),
+                    text(&|a| a.orient.map(|o| o.to_string())),
+                    position(&|a| a.forward.as_ref().map(|p| p.start)),
+                    position(&|a| a.forward.as_ref().map(|p| p.end)),
+                    position(&|a| a.reverse.as_ref().map(|p| p.start)),
+                    position(&|a| a.reverse.as_ref().map(|p| p.end)),
+                    penalty(&|a| a.forward.as_ref().map(|p| p.penalty)),
+                    penalty(&|a| a.reverse.as_ref().map(|p| p.penalty)),
+                    text(&|a| a.sample.clone()),
+                    text(&|a| a.target.clone()),
+                    text(&|a| Some(a.classification.clone())),
+                ];
+                let batch = RecordBatch::try_new(self.schema.clone(), columns)?;
+                if let Some(w) = self.writer.as_mut() {
+                    w.write(&batch)?;
+                }
+                self.rows.clear();
+                Ok(())
+            }
+        }
+
+        impl WriteAssignments for ParquetWriter {
+            fn write(&mut self, assignment: &Assignment) -> Result<(), Box<dyn Error>> {
+                self.rows.push(assignment.clone());
+                if self.rows.len() >= BATCH_SIZE {
+                    self.flush_rows()?;
+                }
+                Ok(())
+            }
+
+            fn finish(&mut self) -> Result<(), Box<dyn Error>> {
+                self.flush_rows()?;
+                if let Some(w) = self.writer.take() {
+                    w.close()?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::primers::{Direction, Primer};
+
+        #[test]
+        fn assignment_fields() {
+            let f = Primer::new("f", b"GATACA", b"", Direction::Forward);
+            let seq = b"GATACATTTTTTTTTT";
+            let candidate = Candidate {
+                primer: &f,
+                hit: f.find_in(seq)[0],
+                rc: false,
+            };
+            let annotation = Annotation {
+                primers: "invalid".to_string(),
+                ..Default::default()
+            };
+            let a = Assignment::new(
+                "r1",
+                &annotation,
+                &Resolution::Unique(candidate),
+                &Resolution::None,
+            );
+            let fields = a.fields();
+            assert_eq!(fields[0], "r1");
+            assert_eq!(fields[1], "f");
+            assert_eq!(fields[2], "");
+            assert_eq!((fields[4].as_str(), fields[5].as_str()), ("0", "6"));
+            assert_eq!(fields[8], "0");
+            assert_eq!(fields[12], "invalid");
+        }
+
+        #[test]
+        fn rejected_fields() {
+            let fields = Assignment::rejected("r1", "malformed:empty").fields();
+            assert_eq!(fields[0], "r1");
+            assert!(fields[1..12].iter().all(|f| f.is_empty()));
+            assert_eq!(fields[12], "malformed:empty");
+        }
+
+        #[cfg(not(feature = "parquet"))]
+        #[test]
+        fn parquet_without_feature_creates_no_file() {
+            let path = std::env::temp_dir().join("myfq_no_feature.parquet");
+            assert!(create(&path).is_err());
+            assert!(!path.exists());
+        }
+
+        #[test]
+        fn tsv_has_header() {
+            let mut buf = Vec::new();
+            {
+                let mut w = TsvWriter::new(&mut buf).unwrap();
+                w.finish().unwrap();
+            }
+            let s = String::from_utf8(buf).unwrap();
+            assert!(s.starts_with("read_id\tforward\treverse\torient"));
+        }
+    }
+}
+
+pub mod config {
+    use serde::{de::DeserializeOwned, Serialize};
+    use std::error::Error;
+
+    /// Overlay the values in the TOML document `file` onto `args`.
+    ///
+    /// `args` is converted to a TOML table with the same keys as the TOML file.
+    /// Each key in `file` replaces the value in `args`, unless `from_cli`
+    /// returns true for that key, i.e. the value was given on the command line.
+    /// Unknown keys are an error if `T` denies unknown fields.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use serde::{Deserialize, Serialize};
+    /// #[derive(Serialize, Deserialize)]
+    /// struct Args { a: u32, b: u32 }
+    ///
+    /// let args = Args { a: 1, b: 2 };
+    /// let merged = myfq::config::overlay(&args, "a = 10\nb = 20", |k| k == "b").unwrap();
+    /// assert_eq!((merged.a, merged.b), (10, 2));
+    /// ```
+    pub fn overlay<T: Serialize + DeserializeOwned>(
+        args: &T,
+        file: &str,
+        from_cli: impl Fn(&str) -> bool,
+    ) -> Result<T, Box<dyn Error>> {
+        let file: toml::Table = toml::from_str(file)?;
+        let mut merged = toml::Table::try_from(args)?;
+        for (key, value) in file {
+            if !from_cli(&key) {
+                merged.insert(key, value);
+            }
+        }
+        Ok(merged.try_into()?)
+    }
+
+    /// Format `args` as a TOML document which can be read back as a configuration file.
+    pub fn to_toml<T: Serialize>(args: &T) -> Result<String, Box<dyn Error>> {
+        Ok(toml::to_string(args)?)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use serde::Deserialize;
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct Args {
+            name: Option<String>,
+            count: usize,
+        }
+
+        #[test]
+        fn fills_missing_options() {
+            let args = Args {
+                name: None,
+                count: 3,
+            };
+            let merged = overlay(&args, "name = \"x\"", |_| false).unwrap();
+            assert_eq!(merged.name.as_deref(), Some("x"));
+            assert_eq!(merged.count, 3);
+        }
+
+        #[test]
+        fn rejects_unknown_keys() {
+            let args = Args {
+                name: None,
+                count: 3,
+            };
+            assert!(overlay(&args, "colour = 1", |_| false).is_err());
+        }
+
+        #[test]
+        fn round_trips() {
+            let args = Args {
+                name: Some("x".to_string()),
+                count: 3,
+            };
+            let text = to_toml(&args).unwrap();
+            assert_eq!(overlay(&args, &text, |_| false).unwrap(), args);
+        }
+    }
+}
+
+pub mod structure {
+    use itertools::Itertools;
+    use std::fmt;
+    use std::str::FromStr;
+
+    /// The kinds of segment which make up a read.
+    #[derive(Debug, PartialEq, Eq, Copy, Clone)]
+    pub enum SegmentKind {
+        Spacer,
+        Barcode,
+        Umi,
+        Primer,
+        Insert,
+    }
+
+    impl SegmentKind {
+        fn name(&self) -> &'static str {
+            match self {
+                SegmentKind::Spacer => "spacer",
+                SegmentKind::Barcode => "barcode",
+                SegmentKind::Umi => "umi",
+                SegmentKind::Primer => "primer",
+                SegmentKind::Insert => "insert",
+            }
+        }
+    }
+
+    /// One bracketed element of a read structure, e.g. `[spacer 0-7]` or `[rc primer]`.
+    ///
+    /// Primer segments take their length from the primer hit.  An insert with
+    /// no length given may be of any length.
+    #[derive(Debug, PartialEq, Eq, Copy, Clone)]
+    pub struct Segment {
+        pub kind: SegmentKind,
+        pub rc: bool,
+        pub min: usize,
+        pub max: usize,
+    }
+
+    impl Segment {
+        /// Name of the segment, e.g. `barcode` or `rc_barcode`.
+        pub fn name(&self) -> String {
+            if self.rc {
+                format!("rc_{}", self.kind.name())
+            } else {
+                self.kind.name().to_string()
+            }
+        }
+
+        /// The segment as written in a read structure, without its length.
+        fn label(&self) -> String {
+            self.name().replace('_', " ")
+        }
+
+        fn is_fixed(&self) -> bool {
+            self.min == self.max
+        }
+    }
+
+    /// A segment located in a read: `start..end` are read positions.
+    #[derive(Debug, PartialEq, Eq, Clone)]
+    pub struct Extracted {
+        pub name: String,
+        pub start: usize,
+        pub end: usize,
+    }
+
+    /// Reasons a read does not fit a read structure.
+    #[derive(Debug, PartialEq, Eq, Clone)]
+    pub enum StructureError {
+        /// A primer in the structure was not found in the read.
+        MissingPrimer(String),
+        /// The primer was found after the reverse complemented primer.
+        Order,
+        /// The bases between two primers, or before the first, do not fit the segments there.
+        Length(String),
+    }
+
+    impl fmt::Display for StructureError {
+        fn fmt(&self, dest: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                StructureError::MissingPrimer(name) => {
+                    write!(dest, "missing-{}", name.replace(' ', "-"))
+                }
+                StructureError::Order => write!(dest, "order"),
+                StructureError::Length(name) => write!(dest, "length-{}", name.replace(' ', "-")),
+            }
+        }
+    }
+
+    /// Expected layout of a read, written as bracketed segments in 5' to 3' order.
+    ///
+    /// Each segment is `[kind]`, `[kind N]` or `[kind N-M]`, optionally with
+    /// `rc` before the kind.  Kinds are `spacer`, `barcode`, `umi`, `primer`
+    /// and `insert`.  `[primer]` is matched to the primer found as itself and
+    /// `[rc primer]` to the primer found as its reverse complement; other
+    /// segments are placed relative to these.  Between two primers, or before
+    /// the first, at most one segment may have a variable length.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use myfq::structure::*;
+    /// let rs: ReadStructure = "[spacer 0-7][barcode 4][primer][insert][rc primer]"
+    ///     .parse()
+    ///     .unwrap();
+    /// // 2 spacer bases, barcode, primer at 6..12, insert, rc primer at 30..36
+    /// let segments = rs.apply(40, Some((6, 12)), Some((30, 36))).unwrap();
+    /// assert_eq!(segments[0].name, "spacer");
+    /// assert_eq!((segments[0].start, segments[0].end), (0, 2));
+    /// assert_eq!((segments[3].start, segments[3].end), (12, 30));
+    ///
+    /// assert!(rs.apply(40, Some((14, 20)), Some((30, 36))).is_err());
+    /// ```
+    #[derive(Debug, PartialEq, Eq, Clone)]
+    pub struct ReadStructure {
+        segments: Vec<Segment>,
+    }
+
+    impl FromStr for ReadStructure {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let mut segments = Vec::new();
+            let mut rest = s.trim();
+            while !rest.is_empty() {
+                let inner = rest
+                    .strip_prefix('[')
+                    .and_then(|r| r.split_once(']'))
+                    .ok_or_else(|| format!("expected '[segment]' at '{rest}'"))?;
+                segments.push(parse_segment(inner.0)?);
+                rest = inner.1.trim_start();
+            }
+            for rc in [false, true] {
+                let primers = segments
+                    .iter()
+                    .filter(|seg| seg.kind == SegmentKind::Primer && seg.rc == rc)
+                    .count();
+                if primers > 1 {
+                    return Err("a primer may appear at most once in each orientation".to_string());
+                }
+            }
+            if let (Some(p), Some(rcp)) = (
+                segments
+                    .iter()
+                    .position(|seg| seg.kind == SegmentKind::Primer && !seg.rc),
+                segments
+                    .iter()
+                    .position(|seg| seg.kind == SegmentKind::Primer && seg.rc),
+            ) {
+                if rcp < p {
+                    return Err("[rc primer] must follow [primer]".to_string());
+                }
+            }
+            let rs = ReadStructure { segments };
+            for group in rs.groups() {
+                if group.iter().filter(|seg| !seg.is_fixed()).count() > 1 {
+                    return Err(format!(
+                        "more than one variable length segment in {}",
+                        group
+                            .iter()
+                            .map(|seg| format!("[{}]", seg.label()))
+                            .join("")
+                    ));
+                }
+            }
+            Ok(rs)
+        }
+    }
+
+    fn parse_segment(text: &str) -> Result<Segment, String> {
+        let mut words = text.split_ascii_whitespace().peekable();
+        let rc = words.next_if_eq(&"rc").is_some();
+        let kind = match words.next() {
+            Some("spacer") => SegmentKind::Spacer,
+            Some("barcode") => SegmentKind::Barcode,
+            Some("umi") => SegmentKind::Umi,
+            Some("primer") => SegmentKind::Primer,
+            Some("insert") => SegmentKind::Insert,
+            other => return Err(format!("unknown segment '{}'", other.unwrap_or_default())),
+        };
+        let (min, max) = match (words.next(), kind) {
+            (None, SegmentKind::Primer) => (0, 0),
+            (None, SegmentKind::Insert) => (0, usize::MAX),
+            (None, _) => return Err(format!("segment '{text}' needs a length")),
+            (Some(_), SegmentKind::Primer) => {
+                return Err("primer length comes from the primers file".to_string())
+            }
+            (Some(len), _) => {
+                let parse = |n: &str| {
+                    n.parse::<usize>()
+                        .map_err(|_| format!("invalid length '{len}' in '{text}'"))
+                };
+                match len.split_once('-') {
+                    Some((lo, hi)) => (parse(lo)?, parse(hi)?),
+                    None => (parse(len)?, parse(len)?),
+                }
+            }
+        };
+        if min > max || words.next().is_some() {
+            return Err(format!("invalid segment '{text}'"));
+        }
+        Ok(Segment { kind, rc, min, max })
+    }
+
+    impl fmt::Display for ReadStructure {
+        fn fmt(&self, dest: &mut fmt::Formatter) -> fmt::Result {
+            for seg in &self.segments {
+                match (seg.kind, seg.min, seg.max) {
+                    (SegmentKind::Primer, ..) | (_, 0, usize::MAX) => {
+                        write!(dest, "[{}]", seg.label())?
+                    }
+                    (_, min, max) if min == max => write!(dest, "[{} {}]", seg.label(), min)?,
+                    (_, min, max) => write!(dest, "[{} {}-{}]", seg.label(), min, max)?,
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl ReadStructure {
+        /// The segments, in order.
+        pub fn segments(&self) -> &[Segment] {
+            &self.segments
+        }
+
+        /// Runs of non-primer segments between primers.
+        fn groups(&self) -> Vec<&[Segment]> {
+            self.segments
+                .split(|seg| seg.kind == SegmentKind::Primer)
+                .collect()
+        }
+
+        /// Locate the segments in a read of length `read_len`.
+        ///
+        /// `primer` and `rc_primer` are the `start, end` positions of the
+        /// primer found as itself and the primer found as its reverse
+        /// complement.  Segments after the last primer may run off the end of
+        /// the read; they are then truncated or omitted.
+        pub fn apply(
+            &self,
+            read_len: usize,
+            primer: Option<(usize, usize)>,
+            rc_primer: Option<(usize, usize)>,
+        ) -> Result<Vec<Extracted>, StructureError> {
+            if let (Some(p), Some(rcp)) = (primer, rc_primer) {
+                if rcp.0 < p.1
+                    && self
+                        .segments
+                        .iter()
+                        .any(|seg| seg.kind == SegmentKind::Primer && seg.rc)
+                {
+                    return Err(StructureError::Order);
+                }
+            }
+            let mut extracted = Vec::new();
+            let mut lo = 0;
+            let mut pending: Vec<Segment> = Vec::new();
+            for seg in &self.segments {
+                if seg.kind != SegmentKind::Primer {
+                    pending.push(*seg);
+                    continue;
+                }
+                let (start, end) = if seg.rc { rc_primer } else { primer }
+                    .ok_or_else(|| StructureError::MissingPrimer(seg.name()))?;
+                if start < lo {
+                    return Err(StructureError::Order);
+                }
+                extracted.extend(fit(&pending, lo, start)?);
+                extracted.push(Extracted {
+                    name: seg.name(),
+                    start,
+                    end,
+                });
+                pending.clear();
+                lo = end;
+            }
+            // trailing segments are laid out from the last primer, as far as the read goes
+            for seg in pending {
+                if lo >= read_len {
+                    break;
+                }
+                let len = if seg.is_fixed() {
+                    seg.min
+                } else {
+                    seg.max.min(read_len - lo)
+                };
+                let end = (lo + len).min(read_len);
+                extracted.push(Extracted {
+                    name: seg.name(),
+                    start: lo,
+                    end,
+                });
+                lo = end;
+            }
+            Ok(extracted)
+        }
+    }
+
+    /// Place `segments` exactly in `lo..hi`, giving any variable length
+    /// segment whatever the fixed ones leave.
+    fn fit(segments: &[Segment], lo: usize, hi: usize) -> Result<Vec<Extracted>, StructureError> {
+        let fixed: usize = segments
+            .iter()
+            .filter(|seg| seg.is_fixed())
+            .map(|seg| seg.min)
+            .sum();
+        let available = hi - lo;
+        let variable = segments.iter().find(|seg| !seg.is_fixed());
+        let spare = available.checked_sub(fixed);
+        let ok = match (variable, spare) {
+            (_, None) => false,
+            (None, Some(spare)) => spare == 0,
+            (Some(v), Some(spare)) => v.min <= spare && spare <= v.max,
+        };
+        if !ok {
+            let name = variable
+                .or(segments.first())
+                .map_or("primer".to_string(), |seg| seg.name());
+            return Err(StructureError::Length(name));
+        }
+        let spare = spare.unwrap_or_default();
+        let mut start = lo;
+        Ok(segments
+            .iter()
+            .map(|seg| {
+                let len = if seg.is_fixed() { seg.min } else { spare };
+                let e = Extracted {
+                    name: seg.name(),
+                    start,
+                    end: start + len,
+                };
+                start += len;
+                e
+            })
+            .collect())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const LAYOUT: &str = "[spacer 0-7][barcode 8][primer][insert][rc primer][rc barcode 8]";
+
+        #[test]
+        fn parse_and_display() {
+            let rs: ReadStructure = LAYOUT.parse().unwrap();
+            assert_eq!(rs.segments().len(), 6);
+            assert_eq!(rs.to_string(), LAYOUT);
+        }
+
+        #[test]
+        fn parse_errors() {
+            assert!("[spacer]".parse::<ReadStructure>().is_err());
+            assert!("[primer 20]".parse::<ReadStructure>().is_err());
+            assert!("[foo 3]".parse::<ReadStructure>().is_err());
+            assert!("[spacer 0-7][insert][primer]"
+                .parse::<ReadStructure>()
+                .is_err());
+            assert!("[rc primer][primer]".parse::<ReadStructure>().is_err());
+            assert!("[primer".parse::<ReadStructure>().is_err());
+        }
+
+        #[test]
+        fn extract_segments() {
+            let rs: ReadStructure = LAYOUT.parse().unwrap();
+            let s = rs.apply(200, Some((11, 31)), Some((150, 170))).unwrap();
+            let names: Vec<_> = s.iter().map(|e| e.name.as_str()).collect();
+            assert_eq!(
+                names,
+                [
+                    "spacer",
+                    "barcode",
+                    "primer",
+                    "insert",
+                    "rc_primer",
+                    "rc_barcode"
+                ]
+            );
+            assert_eq!((s[0].start, s[0].end), (0, 3));
+            assert_eq!((s[1].start, s[1].end), (3, 11));
+            assert_eq!((s[3].start, s[3].end), (31, 150));
+            assert_eq!((s[5].start, s[5].end), (170, 178));
+        }
+
+        #[test]
+        fn trailing_segments_truncated() {
+            let rs: ReadStructure = LAYOUT.parse().unwrap();
+            let s = rs.apply(174, Some((11, 31)), Some((150, 170))).unwrap();
+            assert_eq!((s[5].start, s[5].end), (170, 174));
+        }
+
+        #[test]
+        fn violations() {
+            let rs: ReadStructure = LAYOUT.parse().unwrap();
+            assert_eq!(
+                rs.apply(200, Some((20, 40)), Some((150, 170))),
+                Err(StructureError::Length("spacer".to_string()))
+            );
+            assert_eq!(
+                rs.apply(200, Some((11, 31)), None),
+                Err(StructureError::MissingPrimer("rc_primer".to_string()))
+            );
+            assert_eq!(
+                rs.apply(200, Some((150, 170)), Some((11, 31))),
+                Err(StructureError::Order)
+            );
+        }
+    }
+}
+
+pub mod amplicon {
+    use crate::samples::PrimerPair;
+    use bio::alignment::distance::levenshtein;
+    use bio::io::fasta;
+    use std::collections::HashMap;
+    use std::fmt;
+    use std::io::{self, Read};
+    use std::str::FromStr;
+
+    /// Expected amplicon sequences, in forward primer orientation and
+    /// including the primers, keyed by target name or `forward-reverse` pair.
+    #[derive(Debug, Default)]
+    pub struct References {
+        amplicons: HashMap<String, Vec<u8>>,
+    }
+
+    impl References {
+        pub fn insert(&mut self, key: &str, seq: &[u8]) {
+            self.amplicons
+                .insert(key.to_string(), seq.to_ascii_uppercase());
+        }
+
+        /// The expected amplicon for a read: by target if it has one and
+        /// there is a reference for it, otherwise by primer pair.
+        pub fn get(&self, target: Option<&str>, pair: &PrimerPair) -> Option<&[u8]> {
+            target
+                .and_then(|t| self.amplicons.get(t))
+                .or_else(|| {
+                    self.amplicons
+                        .get(&format!("{}-{}", pair.forward, pair.reverse))
+                })
+                .map(Vec::as_slice)
+        }
+
+        pub fn len(&self) -> usize {
+            self.amplicons.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.amplicons.is_empty()
+        }
+    }
+
+    /// Read expected amplicons from a FASTA file; the record IDs are the keys.
+    pub fn read_references<R: Read>(rdr: R) -> Result<References, io::Error> {
+        let mut references = References::default();
+        for record in fasta::Reader::new(rdr).records() {
+            let record = record?;
+            references.insert(record.id(), record.seq());
+        }
+        Ok(references)
+    }
+
+    /// Fraction of the longer of two sequences not covered by edits between them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use myfq::amplicon::identity;
+    /// assert_eq!(identity(b"GATTACA", b"GATTACA"), 1.0);
+    /// assert_eq!(identity(b"GATTACA", b"GATTAGA"), 1.0 - 1.0 / 7.0);
+    /// assert_eq!(identity(b"", b""), 1.0);
+    /// ```
+    pub fn identity(read: &[u8], expected: &[u8]) -> f64 {
+        let longest = read.len().max(expected.len());
+        if longest == 0 {
+            return 1.0;
+        }
+        1.0 - levenshtein(read, expected) as f64 / longest as f64
+    }
+
+    /// How an assigned read compares to its expected amplicon, written as
+    /// `on:insert:identity` or `off:insert:identity`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use myfq::amplicon::AmpliconMatch;
+    /// let m = AmpliconMatch { insert: 152, identity: 0.9871, on_target: true };
+    /// assert_eq!(m.to_string(), "on:152:0.987");
+    /// assert_eq!("off:40:0.25".parse::<AmpliconMatch>().unwrap().insert, 40);
+    /// ```
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct AmpliconMatch {
+        /// Length of the read between the primers.
+        pub insert: usize,
+        /// [identity] of the read, from primer to primer, to the expected amplicon.
+        pub identity: f64,
+        pub on_target: bool,
+    }
+
+    impl AmpliconMatch {
+        /// Compare `amplicon`, the read from the start of the 5' primer to the
+        /// end of the 3' primer in forward primer orientation, with `expected`.
+        pub fn new(
+            amplicon: &[u8],
+            insert: usize,
+            expected: &[u8],
+            min_identity: f64,
+        ) -> AmpliconMatch {
+            let identity = identity(amplicon, expected);
+            AmpliconMatch {
+                insert,
+                identity,
+                on_target: identity >= min_identity,
+            }
+        }
+    }
+
+    impl fmt::Display for AmpliconMatch {
+        fn fmt(&self, dest: &mut fmt::Formatter) -> fmt::Result {
+            let on = if self.on_target { "on" } else { "off" };
+            write!(dest, "{on}:{}:{:.3}", self.insert, self.identity)
+        }
+    }
+
+    impl FromStr for AmpliconMatch {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let bad = || format!("invalid amplicon match '{s}'");
+            let mut parts = s.splitn(3, ':');
+            let on_target = match parts.next() {
+                Some("on") => true,
+                Some("off") => false,
+                _ => return Err(bad()),
+            };
+            let insert = parts.next().and_then(|n| n.parse().ok()).ok_or_else(bad)?;
+            let identity = parts.next().and_then(|n| n.parse().ok()).ok_or_else(bad)?;
+            Ok(AmpliconMatch {
+                insert,
+                identity,
+                on_target,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn pair(f: &str, r: &str) -> PrimerPair {
+            PrimerPair {
+                forward: f.to_string(),
+                reverse: r.to_string(),
+            }
+        }
+
+        #[test]
+        fn references_by_target_or_pair() {
+            let fasta = ">amp1\nGATACAGG\nTTTT\n>oVK002-oVK010\nccattgac\n";
+            let refs = read_references(fasta.as_bytes()).unwrap();
+            assert_eq!(refs.len(), 2);
+            let p = pair("oVK001", "oVK010");
+            assert_eq!(refs.get(Some("amp1"), &p), Some(&b"GATACAGGTTTT"[..]));
+            assert_eq!(refs.get(None, &p), None);
+            let p = pair("oVK002", "oVK010");
+            assert_eq!(refs.get(Some("other"), &p), Some(&b"CCATTGAC"[..]));
+        }
+
+        #[test]
+        fn off_target_below_min_identity() {
+            let m = AmpliconMatch::new(b"GATACAGGTTTT", 4, b"GATACAGGTTTT", 0.9);
+            assert!(m.on_target);
+            let m = AmpliconMatch::new(b"GATACAGGCCCC", 4, b"GATACAGGTTTT", 0.9);
+            assert!(!m.on_target);
+            assert_eq!(m.to_string(), "off:4:0.667");
+        }
+    }
+}
+
+pub mod asv {
+    use std::collections::{BTreeMap, BTreeSet, HashMap};
+    use std::io::{self, Write};
+
+    /// A stable identifier for a sequence: `asv_` & the 64 bit FNV-1a hash
+    /// of the upper case sequence, in hex.
+    ///
+    /// Unlike the standard library hashers, this gives the same ID across runs,
+    /// platforms & versions, so tables from different runs can be joined.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use myfq::asv::asv_id;
+    /// assert_eq!(asv_id(b"ACGT"), asv_id(b"acgt"));
+    /// assert_eq!(asv_id(b""), "asv_cbf29ce484222325");
+    /// ```
+    pub fn asv_id(seq: &[u8]) -> String {
+        const OFFSET: u64 = 0xcbf29ce484222325;
+        const PRIME: u64 = 0x100000001b3;
+        let hash = seq.iter().fold(OFFSET, |h, b| {
+            (h ^ u64::from(b.to_ascii_uppercase())).wrapping_mul(PRIME)
+        });
+        format!("asv_{hash:016x}")
+    }
+
+    /// A unique insert sequence & its read count in each sample.
+    #[derive(Debug, PartialEq)]
+    pub struct Asv {
+        pub id: String,
+        pub seq: Vec<u8>,
+        pub counts: BTreeMap<String, usize>,
+    }
+
+    impl Asv {
+        pub fn total(&self) -> usize {
+            self.counts.values().sum()
+        }
+    }
+
+    /// Insert sequences dereplicated across samples.
+    #[derive(Debug, Default)]
+    pub struct Dereplicator {
+        counts: HashMap<Vec<u8>, BTreeMap<String, usize>>,
+    }
+
+    impl Dereplicator {
+        /// Count one read of `sample` with insert `seq`, in forward primer orientation.
+        pub fn add(&mut self, sample: &str, seq: &[u8]) {
+            *self
+                .counts
+                .entry(seq.to_ascii_uppercase())
+                .or_default()
+                .entry(sample.to_string())
+                .or_insert(0) += 1;
+        }
+
+        /// The sequences with at least `min_abundance` reads over all samples,
+        /// most abundant first.
+        pub fn asvs(&self, min_abundance: usize) -> Vec<Asv> {
+            let mut asvs: Vec<Asv> = self
+                .counts
+                .iter()
+                .map(|(seq, counts)| Asv {
+                    id: asv_id(seq),
+                    seq: seq.clone(),
+                    counts: counts.clone(),
+                })
+                .filter(|asv| asv.total() >= min_abundance)
+                .collect();
+            asvs.sort_by(|a, b| b.total().cmp(&a.total()).then_with(|| a.id.cmp(&b.id)));
+            asvs
+        }
+    }
+
+    /// Write a TSV matrix with one row per sample & one column per ASV.
+    ///
+    /// Every sample in `samples` gets a row, even with no reads.
+    pub fn write_table<W: Write>(
+        mut dest: W,
+        samples: &BTreeSet<String>,
+        asvs: &[Asv],
+    ) -> io::Result<()> {
+        write!(dest, "sample")?;
+        for asv in asvs {
+            write!(dest, "\t{}", asv.id)?;
+        }
+        writeln!(dest)?;
+        for sample in samples {
+            write!(dest, "{sample}")?;
+            for asv in asvs {
+                write!(dest, "\t{}", asv.counts.get(sample).unwrap_or(&0))?;
+            }
+            writeln!(dest)?;
+        }
+        Ok(())
+    }
+
+    /// Write the ASV sequences as FASTA, with their total read count as `size=`.
+    pub fn write_fasta<W: Write>(mut dest: W, asvs: &[Asv]) -> io::Result<()> {
+        for asv in asvs {
+            writeln!(dest, ">{} size={}", asv.id, asv.total())?;
+            dest.write_all(&asv.seq)?;
+            writeln!(dest)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn dereplicated() -> Dereplicator {
+            let mut d = Dereplicator::default();
+            d.add("S1", b"ACGTACGT");
+            d.add("S1", b"ACGTACGT");
+            d.add("S2", b"acgtacgt");
+            d.add("S2", b"TTTT");
+            d
+        }
+
+        #[test]
+        fn dereplicate_across_samples() {
+            let asvs = dereplicated().asvs(1);
+            assert_eq!(asvs.len(), 2);
+            assert_eq!(asvs[0].seq, b"ACGTACGT");
+            assert_eq!(asvs[0].counts["S1"], 2);
+            assert_eq!(asvs[0].counts["S2"], 1);
+            assert_eq!(dereplicated().asvs(2).len(), 1);
+        }
+
+        #[test]
+        fn table_has_every_sample() {
+            let asvs = dereplicated().asvs(2);
+            let samples: BTreeSet<String> = ["S1", "S2", "S3"].map(String::from).into();
+            let mut table = Vec::new();
+            write_table(&mut table, &samples, &asvs).unwrap();
+            let id = asv_id(b"ACGTACGT");
+            assert_eq!(
+                String::from_utf8(table).unwrap(),
+                format!("sample\t{id}\nS1\t2\nS2\t1\nS3\t0\n")
+            );
+            let mut fasta = Vec::new();
+            write_fasta(&mut fasta, &asvs).unwrap();
+            assert_eq!(
+                String::from_utf8(fasta).unwrap(),
+                format!(">{id} size=3\nACGTACGT\n")
+            );
+        }
+    }
+}
+
+pub mod consensus {
+    use bio::alignment::pairwise::Aligner;
+    use bio::alignment::AlignmentOperation;
+    use std::collections::HashMap;
+
+    const BASES: [u8; 4] = [b'A', b'C', b'G', b'T'];
+    const GAP: usize = 4;
+
+    /// The IUPAC code for a set of bases, given as flags for A, C, G & T.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use myfq::consensus::iupac;
+    /// assert_eq!(iupac([true, false, false, false]), b'A');
+    /// assert_eq!(iupac([true, false, true, false]), b'R');
+    /// assert_eq!(iupac([false; 4]), b'N');
+    /// ```
+    pub fn iupac(bases: [bool; 4]) -> u8 {
+        match bases {
+            [true, false, false, false] => b'A',
+            [false, true, false, false] => b'C',
+            [false, false, true, false] => b'G',
+            [false, false, false, true] => b'T',
+            [true, false, true, false] => b'R',
+            [false, true, false, true] => b'Y',
+            [false, true, true, false] => b'S',
+            [true, false, false, true] => b'W',
+            [false, false, true, true] => b'K',
+            [true, true, false, false] => b'M',
+            [false, true, true, true] => b'B',
+            [true, false, true, true] => b'D',
+            [true, true, false, true] => b'H',
+            [true, true, true, false] => b'V',
+            _ => b'N',
+        }
+    }
+
+    /// One position of a consensus: the base called, the number of reads
+    /// covering it & the fraction of them with a base in the call.
+    #[derive(Debug, PartialEq)]
+    pub struct Position {
+        pub base: u8,
+        pub depth: usize,
+        pub support: f64,
+    }
+
+    /// A consensus of the reads of one sample.
+    #[derive(Debug, PartialEq)]
+    pub struct Consensus {
+        pub reads: usize,
+        pub positions: Vec<Position>,
+    }
+
+    impl Consensus {
+        pub fn seq(&self) -> Vec<u8> {
+            self.positions.iter().map(|p| p.base).collect()
+        }
+    }
+
+    /// Reads of one sample, dereplicated, in forward primer orientation.
+    #[derive(Debug, Default)]
+    pub struct Pileup {
+        reads: HashMap<Vec<u8>, usize>,
+    }
+
+    impl Pileup {
+        pub fn add(&mut self, seq: &[u8]) {
+            *self.reads.entry(seq.to_ascii_uppercase()).or_insert(0) += 1;
+        }
+
+        /// Call a consensus by aligning every read to the most common one.
+        ///
+        /// Each base with at least `min_fraction` of the reads at a position
+        /// is part of the call, as an IUPAC code if there is more than one.
+        /// Positions where most reads have a deletion are left out; insertions
+        /// relative to the most common read are ignored.
+        ///
+        /// # Examples
+        ///
+        /// ```
+        /// use myfq::consensus::Pileup;
+        /// let mut pileup = Pileup::default();
+        /// for seq in ["GATTACA", "GATTACA", "GATCACA", "GATCACA", "GATTACA"] {
+        ///     pileup.add(seq.as_bytes());
+        /// }
+        /// let consensus = pileup.consensus(0.3).unwrap();
+        /// assert_eq!(consensus.seq(), b"GATYACA");
+        /// assert_eq!(consensus.positions[3].support, 1.0);
+        /// assert_eq!(pileup.consensus(0.5).unwrap().seq(), b"GATTACA");
+        /// ```
+        pub fn consensus(&self, min_fraction: f64) -> Option<Consensus> {
+            let (dominant, _) = self
+                .reads
+                .iter()
+                .max_by(|(s1, n1), (s2, n2)| n1.cmp(n2).then_with(|| s2.cmp(s1)))?;
+
+            // counts of A, C, G, T & deletions at each position of the dominant read
+            let mut counts = vec![[0usize; 5]; dominant.len()];
+            let score = |a: u8, b: u8| if a == b { 1i32 } else { -1i32 };
+            let mut aligner = Aligner::new(-5, -1, score);
+            for (seq, &n) in &self.reads {
+                let alignment = aligner.global(seq, dominant);
+                let (mut x, mut y) = (0, 0);
+                for op in alignment.operations {
+                    match op {
+                        AlignmentOperation::Match | AlignmentOperation::Subst => {
+                            if let Some(i) = BASES.iter().position(|&b| b == seq[x]) {
+                                counts[y][i] += n;
+                            }
+                            x += 1;
+                            y += 1;
+                        }
+                        AlignmentOperation::Del => {
+                            counts[y][GAP] += n;
+                            y += 1;
+                        }
+                        AlignmentOperation::Ins => x += 1,
+                        AlignmentOperation::Xclip(n) => x += n,
+                        AlignmentOperation::Yclip(n) => y += n,
+                    }
+                }
+            }
+
+            let positions = counts
+                .iter()
+                .filter_map(|c| {
+                    let depth: usize = c.iter().sum();
+                    if depth == 0 || c[GAP] * 2 > depth {
+                        return None;
+                    }
+                    let called = [0, 1, 2, 3].map(|i| c[i] as f64 >= min_fraction * depth as f64);
+                    let supporting: usize = (0..4).filter(|&i| called[i]).map(|i| c[i]).sum();
+                    Some(Position {
+                        base: iupac(called),
+                        depth,
+                        support: supporting as f64 / depth as f64,
+                    })
+                })
+                .collect();
+            Some(Consensus {
+                reads: self.reads.values().sum(),
+                positions,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn consensus_across_indels() {
+            let mut pileup = Pileup::default();
+            for seq in [
+                "GATTACAGATTACA",
+                "GATTACAGATTACA",
+                "GATTACGATTACA",
+                "GATTACAGGATTACA",
+            ] {
+                pileup.add(seq.as_bytes());
+            }
+            let consensus = pileup.consensus(0.5).unwrap();
+            assert_eq!(consensus.seq(), b"GATTACAGATTACA");
+            assert_eq!(consensus.reads, 4);
+            assert!(consensus.positions.iter().all(|p| p.depth == 4));
+            assert!(consensus.positions.iter().any(|p| p.support == 0.75));
+        }
+
+        #[test]
+        fn empty_pileup() {
+            assert_eq!(Pileup::default().consensus(0.5), None);
+        }
+    }
+}
+
+pub mod umi {
+    use std::collections::{BTreeMap, HashMap};
+    use std::io::{self, Write};
+
+    /// Where the UMI is in a read: `offset` bases from the start of the
+    /// primer at the 5' end of the read, negative for bases before it.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct UmiLayout {
+        pub offset: isize,
+        pub length: usize,
+    }
+
+    impl UmiLayout {
+        /// The position of the UMI given the start of the 5' primer hit, if it is within the read.
+        ///
+        /// # Examples
+        ///
+        /// ```
+        /// use myfq::umi::UmiLayout;
+        /// let layout = UmiLayout { offset: -8, length: 8 };
+        /// assert_eq!(layout.locate(12, 100), Some(4..12));
+        /// assert_eq!(layout.locate(4, 100), None);
+        /// ```
+        pub fn locate(
+            &self,
+            primer_start: usize,
+            read_len: usize,
+        ) -> Option<std::ops::Range<usize>> {
+            let start = primer_start.checked_add_signed(self.offset)?;
+            let end = start + self.length;
+            (end <= read_len).then_some(start..end)
+        }
+    }
+
+    fn hamming(a: &[u8], b: &[u8]) -> usize {
+        a.iter().zip(b).filter(|(x, y)| x != y).count() + a.len().abs_diff(b.len())
+    }
+
+    /// A group of UMIs taken to come from one molecule.
+    #[derive(Debug, PartialEq)]
+    pub struct Cluster {
+        /// The most common UMI of the cluster.
+        pub umi: Vec<u8>,
+        pub umis: usize,
+        pub reads: usize,
+    }
+
+    /// Cluster UMIs with the directional method of UMI-tools.
+    ///
+    /// A UMI absorbs another within `max_distance` mismatches if it has at
+    /// least twice as many reads, less one, as the other; absorbed UMIs in turn
+    /// absorb their neighbours.  Clusters are seeded from the most common UMI.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use myfq::umi::directional_clusters;
+    /// use std::collections::HashMap;
+    /// let counts = HashMap::from([
+    ///     (b"AAAA".to_vec(), 10),
+    ///     (b"AAAT".to_vec(), 2),
+    ///     (b"AATT".to_vec(), 1),
+    ///     (b"GGGG".to_vec(), 3),
+    /// ]);
+    /// let clusters = directional_clusters(&counts, 1);
+    /// assert_eq!(clusters.len(), 2);
+    /// assert_eq!(clusters[0].umi, b"AAAA");
+    /// assert_eq!(clusters[0].reads, 13);
+    /// ```
+    pub fn directional_clusters(
+        counts: &HashMap<Vec<u8>, usize>,
+        max_distance: usize,
+    ) -> Vec<Cluster> {
+        let mut umis: Vec<(&Vec<u8>, usize)> = counts.iter().map(|(u, &n)| (u, n)).collect();
+        umis.sort_by(|(u1, n1), (u2, n2)| n2.cmp(n1).then_with(|| u1.cmp(u2)));
+
+        let mut assigned = vec![false; umis.len()];
+        let mut clusters = Vec::new();
+        for seed in 0..umis.len() {
+            if assigned[seed] {
+                continue;
+            }
+            assigned[seed] = true;
+            let mut cluster = Cluster {
+                umi: umis[seed].0.clone(),
+                umis: 0,
+                reads: 0,
+            };
+            let mut queue = vec![seed];
+            while let Some(i) = queue.pop() {
+                let (umi, n) = umis[i];
+                cluster.umis += 1;
+                cluster.reads += n;
+                for j in 0..umis.len() {
+                    let (other, m) = umis[j];
+                    if !assigned[j] && n + 1 >= 2 * m && hamming(umi, other) <= max_distance {
+                        assigned[j] = true;
+                        queue.push(j);
+                    }
+                }
+            }
+            clusters.push(cluster);
+        }
+        clusters
+    }
+
+    /// UMIs seen in each sample.
+    #[derive(Debug, Default)]
+    pub struct UmiCounts {
+        by_sample: BTreeMap<String, HashMap<Vec<u8>, usize>>,
+    }
+
+    impl UmiCounts {
+        pub fn add(&mut self, sample: &str, umi: &[u8]) {
+            *self
+                .by_sample
+                .entry(sample.to_string())
+                .or_default()
+                .entry(umi.to_vec())
+                .or_insert(0) += 1;
+        }
+
+        /// Write a TSV of reads, distinct UMIs & molecules per sample.
+        pub fn write_molecules<W: Write>(
+            &self,
+            mut dest: W,
+            max_distance: usize,
+        ) -> io::Result<()> {
+            writeln!(dest, "sample\treads\tumis\tmolecules")?;
+            for (sample, counts) in &self.by_sample {
+                let clusters = directional_clusters(counts, max_distance);
+                let reads: usize = counts.values().sum();
+                writeln!(
+                    dest,
+                    "{sample}\t{reads}\t{}\t{}",
+                    counts.len(),
+                    clusters.len()
+                )?;
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn similar_counts_are_not_merged() {
+            let counts = HashMap::from([(b"AAAA".to_vec(), 5), (b"AAAT".to_vec(), 4)]);
+            assert_eq!(directional_clusters(&counts, 1).len(), 2);
+            assert_eq!(directional_clusters(&counts, 0).len(), 2);
+        }
+
+        #[test]
+        fn molecules_per_sample() {
+            let mut counts = UmiCounts::default();
+            for umi in ["ACGT", "ACGT", "ACGT", "ACGA", "TTTT"] {
+                counts.add("S1", umi.as_bytes());
+            }
+            counts.add("S2", b"ACGT");
+            let mut out = Vec::new();
+            counts.write_molecules(&mut out, 1).unwrap();
+            assert_eq!(
+                String::from_utf8(out).unwrap(),
+                "sample\treads\tumis\tmolecules\nS1\t5\t3\t2\nS2\t1\t1\t1\n"
+            );
+        }
+    }
+}
+
+pub mod input {
+    use bio::io::fastq;
+    use serde::{Deserialize, Serialize};
+    use std::str::FromStr;
+
+    /// What to do with a FASTQ record which cannot be parsed or fails its check.
+    #[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum OnError {
+        /// Report the record & carry on with the next one.
+        Skip,
+        /// Report the record & stop reading.
+        Fail,
+    }
+
+    impl FromStr for OnError {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "skip" => Ok(OnError::Skip),
+                "fail" => Ok(OnError::Fail),
+                _ => Err(format!("unknown error policy '{s}', expected skip or fail")),
+            }
+        }
+    }
+
+    /// Whether reading must stop after `error`, whatever the policy.
+    ///
+    /// An incomplete record means the input was truncated, and I/O errors
+    /// other than invalid data will not go away by reading on.
+    pub fn is_fatal(error: &fastq::Error) -> bool {
+        match error {
+            fastq::Error::MissingAt => false,
+            fastq::Error::ReadError(e) => e.kind() != std::io::ErrorKind::InvalidData,
+            _ => true,
+        }
+    }
+
+    /// Whether `error` shows that the input ended part way through a record.
+    pub fn is_truncation(error: &fastq::Error) -> bool {
+        matches!(error, fastq::Error::IncompleteRecord)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn read_all(text: &str) -> Vec<Result<fastq::Record, fastq::Error>> {
+            fastq::Reader::new(text.as_bytes()).records().collect()
+        }
+
+        #[test]
+        fn parse_policy() {
+            assert_eq!("skip".parse::<OnError>(), Ok(OnError::Skip));
+            assert!("ignore".parse::<OnError>().is_err());
+        }
+
+        #[test]
+        fn truncated_input_is_fatal() {
+            let results = read_all("@r1\nACGT\n+\nIIII\n@r2\nACGT\n");
+            assert!(results[0].is_ok());
+            let e = results[1].as_ref().unwrap_err();
+            assert!(is_truncation(e));
+            assert!(is_fatal(e));
+        }
+
+        #[test]
+        fn missing_at_can_be_skipped() {
+            let results = read_all("r1\n@r2\nACGT\n+\nIIII\n");
+            let e = results[0].as_ref().unwrap_err();
+            assert!(!is_fatal(e));
+            assert_eq!(results[1].as_ref().unwrap().id(), "r2");
+        }
+    }
+}
+
+pub mod merge {
+    use bio::alphabets::dna;
+    use std::fmt;
+
+    /// Parameters for merging the two reads of a pair where they overlap.
+    ///
+    /// The second read is reverse complemented and slid along the first.  Of
+    /// the overlaps of at least `min_overlap` bases with at most
+    /// `max_mismatch_rate` mismatches per base, the one with the most matches
+    /// less mismatches is used; ties go to the longer overlap.
+    ///
+    /// The merged read runs from the start of the first read to the end of
+    /// the second.  If the insert is shorter than the reads, each read runs
+    /// through into the adapter at the other end; those overhangs are dropped.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct Merger {
+        pub min_overlap: usize,
+        pub max_mismatch_rate: f64,
+    }
+
+    impl Default for Merger {
+        fn default() -> Self {
+            Merger {
+                min_overlap: 10,
+                max_mismatch_rate: 0.1,
+            }
+        }
+    }
+
+    /// A read made by merging a pair, with Phred+33 qualities.
+    #[derive(Debug, PartialEq)]
+    pub struct Merged {
+        pub seq: Vec<u8>,
+        pub qual: Vec<u8>,
+        pub overlap: usize,
+        pub mismatches: usize,
+    }
+
+    /// Call one base from two reads of the same position.
+    ///
+    /// Agreeing bases keep the higher quality.  Of disagreeing bases the one
+    /// with the higher quality is kept, with the difference in quality; if
+    /// neither is better the base is `N`.
+    fn consensus(b1: u8, q1: u8, b2: u8, q2: u8) -> (u8, u8) {
+        const MIN_QUAL: u8 = b'#';
+        if b1 == b2 {
+            (b1, q1.max(q2))
+        } else if q1 == q2 {
+            (b'N', MIN_QUAL)
+        } else {
+            let (b, q) = if q1 > q2 { (b1, q1) } else { (b2, q2) };
+            (b, (q - q1.min(q2) + b'!').max(MIN_QUAL))
+        }
+    }
+
+    impl Merger {
+        /// Merge a pair of reads, or `None` if no overlap is good enough or
+        /// either read has a different number of bases & qualities.
+        ///
+        /// # Examples
+        ///
+        /// ```
+        /// use myfq::merge::Merger;
+        /// use bio::alphabets::dna;
+        /// let insert = b"GATACAGGAAAAACCCCCGGGGGTTTTTCCATTGAC";
+        /// let r1 = &insert[..24];
+        /// let r2 = dna::revcomp(&insert[12..]);
+        /// let merger = Merger::default();
+        /// let merged = merger.merge(r1, &[b'I'; 24], &r2, &[b'I'; 24]).unwrap();
+        /// assert_eq!(merged.seq, insert);
+        /// assert_eq!(merged.overlap, 12);
+        /// ```
+        pub fn merge(
+            &self,
+            seq1: &[u8],
+            qual1: &[u8],
+            seq2: &[u8],
+            qual2: &[u8],
+        ) -> Option<Merged> {
+            if seq1.len() != qual1.len() || seq2.len() != qual2.len() {
+                return None;
+            }
+            let seq2 = dna::revcomp(seq2);
+            let qual2: Vec<u8> = qual2.iter().rev().copied().collect();
+            let min_overlap = self.min_overlap.max(1);
+            if seq1.len() < min_overlap || seq2.len() < min_overlap {
+                return None;
+            }
+
+            // the second read starts `offset` bases after the first, or
+            // before it when the insert is shorter than the second read
+            let (len1, len2) = (seq1.len() as isize, seq2.len() as isize);
+            let mut best: Option<(isize, usize, usize)> = None;
+            for offset in min_overlap as isize - len2..=len1 - min_overlap as isize {
+                let start1 = offset.max(0) as usize;
+                let start2 = (-offset).max(0) as usize;
+                let overlap = (len1.min(offset + len2) - offset.max(0)) as usize;
+                let mismatches = seq1[start1..start1 + overlap]
+                    .iter()
+                    .zip(&seq2[start2..start2 + overlap])
+                    .filter(|(a, b)| a != b)
+                    .count();
+                if mismatches as f64 > self.max_mismatch_rate * overlap as f64 {
+                    continue;
+                }
+                let better = match best {
+                    None => true,
+                    Some((_, o, m)) => {
+                        // compare matches less mismatches, overlap - 2 * mismatches
+                        let (score, best_score) = (overlap + 2 * m, o + 2 * mismatches);
+                        score > best_score || (score == best_score && overlap > o)
+                    }
+                };
+                if better {
+                    best = Some((offset, overlap, mismatches));
+                }
+            }
+
+            let (offset, overlap, mismatches) = best?;
+            let start1 = offset.max(0) as usize;
+            let start2 = (-offset).max(0) as usize;
+            let mut seq = seq1[..start1].to_vec();
+            let mut qual = qual1[..start1].to_vec();
+            for i in 0..overlap {
+                let (b, q) = consensus(
+                    seq1[start1 + i],
+                    qual1[start1 + i],
+                    seq2[start2 + i],
+                    qual2[start2 + i],
+                );
+                seq.push(b);
+                qual.push(q);
+            }
+            seq.extend_from_slice(&seq2[start2 + overlap..]);
+            qual.extend_from_slice(&qual2[start2 + overlap..]);
+            Some(Merged {
+                seq,
+                qual,
+                overlap,
+                mismatches,
+            })
+        }
+    }
+
+    /// Counts of read pairs merged or not, for the run summary.
+    #[derive(Debug, Default)]
+    pub struct MergeStats {
+        pub pairs: usize,
+        pub merged: usize,
+        pub overlap_total: usize,
+        pub mismatch_total: usize,
+    }
+
+    impl MergeStats {
+        pub fn add(&mut self, merged: Option<&Merged>) {
+            self.pairs += 1;
+            if let Some(m) = merged {
+                self.merged += 1;
+                self.overlap_total += m.overlap;
+                self.mismatch_total += m.mismatches;
+            }
+        }
+    }
+
+    impl fmt::Display for MergeStats {
+        fn fmt(&self, dest: &mut fmt::Formatter) -> fmt::Result {
+            writeln!(dest, "pairs merged: {}/{}", self.merged, self.pairs)?;
+            writeln!(dest, "  unmerged: {}", self.pairs - self.merged)?;
+            if self.merged > 0 {
+                let mean = self.overlap_total as f64 / self.merged as f64;
+                writeln!(dest, "  mean overlap: {mean:.1}")?;
+                writeln!(dest, "  overlap mismatches: {}", self.mismatch_total)?;
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn disagreeing_bases_take_better_quality() {
+            assert_eq!(consensus(b'A', b'I', b'C', b'+'), (b'A', b'!' + 30));
+            assert_eq!(consensus(b'A', b'5', b'C', b'5'), (b'N', b'#'));
+            assert_eq!(consensus(b'G', b'5', b'G', b'I'), (b'G', b'I'));
+        }
+
+        #[test]
+        fn merge_with_mismatch_in_overlap() {
+            let insert = b"GATACAGGAAAAACCCCCGGGGGTTTTTCCATTGAC";
+            let r1 = &insert[..24];
+            let mut r2 = insert[12..].to_vec();
+            r2[2] = b'A';
+            let r2 = dna::revcomp(&r2);
+            let mut q2 = vec![b'I'; 24];
+            q2[21] = b'#';
+            let merged = Merger::default().merge(r1, &[b'I'; 24], &r2, &q2).unwrap();
+            assert_eq!(merged.seq, insert);
+            assert_eq!(merged.mismatches, 1);
+        }
+
+        #[test]
+        fn long_overlap_beats_short_perfect_one() {
+            // the last 10 bases of r1 also match the first 10 of r2, perfectly
+            let insert = b"GATACAGGAAACGTTGCAGTACGTTGCAGTCCATTGACTT";
+            let mut r1 = insert[..30].to_vec();
+            r1[15] = b'A';
+            let mut q1 = vec![b'I'; 30];
+            q1[15] = b'#';
+            let r2 = dna::revcomp(&insert[10..]);
+            let merged = Merger::default().merge(&r1, &q1, &r2, &[b'I'; 30]).unwrap();
+            assert_eq!(merged.seq, insert);
+            assert_eq!((merged.overlap, merged.mismatches), (20, 1));
+        }
+
+        #[test]
+        fn read_through_drops_adapters() {
+            let insert = b"GATACAGGAAAAACCCCCGG";
+            let r1 = [&insert[..], b"AGATCGGAAG"].concat();
+            let r2 = [dna::revcomp(insert), b"AGATCGGAAG".to_vec()].concat();
+            let merged = Merger::default()
+                .merge(&r1, &[b'I'; 30], &r2, &[b'I'; 30])
+                .unwrap();
+            assert_eq!(merged.seq, insert);
+            assert_eq!(merged.qual.len(), insert.len());
+            assert_eq!((merged.overlap, merged.mismatches), (20, 0));
+        }
+
+        #[test]
+        fn unequal_qualities_are_not_merged() {
+            let insert = b"GATACAGGAAAAACCCCCGGGGGTTTTTCCATTGAC";
+            let r1 = &insert[..24];
+            let r2 = dna::revcomp(&insert[12..]);
+            let merger = Merger::default();
+            assert_eq!(merger.merge(r1, &[b'I'; 24], &r2, &[b'I'; 4]), None);
+            assert_eq!(merger.merge(r1, &[b'I'; 30], &r2, &[b'I'; 24]), None);
+        }
+
+        #[test]
+        fn no_overlap() {
+            let merger = Merger::default();
+            assert_eq!(
+                merger.merge(
+                    b"AAAAAAAAAAAA",
+                    b"IIIIIIIIIIII",
+                    b"AAAAAAAAAAAA",
+                    b"IIIIIIIIIIII"
+                ),
+                None
+            );
+            assert_eq!(merger.merge(b"ACGT", b"IIII", b"ACGT", b"IIII"), None);
+        }
+    }
+}
+
+pub mod screen {
+    use bio::io::fasta;
+    use std::collections::HashMap;
+    use std::io::{self, Read};
+
+    fn encode(base: u8) -> Option<u64> {
+        match base.to_ascii_uppercase() {
+            b'A' => Some(0),
+            b'C' => Some(1),
+            b'G' => Some(2),
+            b'T' => Some(3),
+            _ => None,
+        }
+    }
+
+    /// The canonical k-mers of `seq`, the lesser of each k-mer & its reverse
+    /// complement, packed two bits per base.  K-mers containing anything but
+    /// A, C, G or T are skipped.
+    fn canonical_kmers(seq: &[u8], k: usize) -> impl Iterator<Item = u64> + '_ {
+        let mask = if k >= 32 {
+            u64::MAX
+        } else {
+            (1 << (2 * k)) - 1
+        };
+        let shift = 2 * (k as u64 - 1);
+        let (mut fwd, mut rev, mut valid) = (0u64, 0u64, 0usize);
+        seq.iter().filter_map(move |&b| {
+            match encode(b) {
+                Some(x) => {
+                    fwd = ((fwd << 2) | x) & mask;
+                    rev = (rev >> 2) | ((3 - x) << shift);
+                    valid += 1;
+                }
+                None => valid = 0,
+            }
+            (valid >= k).then(|| fwd.min(rev))
+        })
+    }
+
+    /// K-mers of contaminant references, e.g. PhiX, to screen reads against.
+    ///
+    /// A read is a contaminant if at least `min_fraction` of its k-mers are
+    /// found in one reference; it is named by the reference sharing the most.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use myfq::screen::KmerScreen;
+    /// let mut screen = KmerScreen::new(5, 0.5);
+    /// screen.add_reference("phix", b"GAGTTTTATCGCTTCCATGACGCAGAAGTTAACACTTTCGGATATTTCTGATGAGTCG");
+    /// assert_eq!(screen.classify(b"CGCTTCCATGACGCAGAAGTT"), Some("phix"));
+    /// assert_eq!(screen.classify(b"AACTTCTGCGTCATGGAAGCG"), Some("phix"));
+    /// assert_eq!(screen.classify(b"GATACAGGACGTTGCAGTCAG"), None);
+    /// ```
+    #[derive(Debug)]
+    pub struct KmerScreen {
+        k: usize,
+        min_fraction: f64,
+        names: Vec<String>,
+        kmers: HashMap<u64, usize>,
+    }
+
+    impl KmerScreen {
+        /// An empty screen with k-mers of length `k`, at most 32.
+        pub fn new(k: usize, min_fraction: f64) -> KmerScreen {
+            assert!((1..=32).contains(&k), "k-mer length must be 1 to 32");
+            KmerScreen {
+                k,
+                min_fraction,
+                names: Vec::new(),
+                kmers: HashMap::new(),
+            }
+        }
+
+        /// Add the k-mers of a reference sequence; a k-mer in more than one
+        /// reference is kept for the first.
+        pub fn add_reference(&mut self, name: &str, seq: &[u8]) {
+            let index = match self.names.iter().position(|n| n == name) {
+                Some(i) => i,
+                None => {
+                    self.names.push(name.to_string());
+                    self.names.len() - 1
+                }
+            };
+            for kmer in canonical_kmers(seq, self.k) {
+                self.kmers.entry(kmer).or_insert(index);
+            }
+        }
+
+        /// Add every sequence of a FASTA file under `name`.
+        pub fn add_fasta<R: Read>(&mut self, name: &str, rdr: R) -> Result<(), io::Error> {
+            for record in fasta::Reader::new(rdr).records() {
+                self.add_reference(name, record?.seq());
+            }
+            Ok(())
+        }
+
+        pub fn names(&self) -> &[String] {
+            &self.names
+        }
+
+        /// The reference `seq` comes from, if it shares enough k-mers with one.
+        pub fn classify(&self, seq: &[u8]) -> Option<&str> {
+            let mut total = 0;
+            let mut shared = vec![0usize; self.names.len()];
+            for kmer in canonical_kmers(seq, self.k) {
+                total += 1;
+                if let Some(&i) = self.kmers.get(&kmer) {
+                    shared[i] += 1;
+                }
+            }
+            let (best, &count) = shared.iter().enumerate().max_by_key(|(_, &n)| n)?;
+            (total > 0 && count as f64 >= self.min_fraction * total as f64)
+                .then(|| self.names[best].as_str())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn canonical_kmers_match_reverse_complement() {
+            let fwd: Vec<u64> = canonical_kmers(b"GATTACA", 3).collect();
+            let mut rev: Vec<u64> = canonical_kmers(b"TGTAATC", 3).collect();
+            rev.reverse();
+            assert_eq!(fwd, rev);
+            assert_eq!(canonical_kmers(b"GANTACA", 3).count(), 2);
+        }
+
+        #[test]
+        fn best_reference_wins() {
+            let mut screen = KmerScreen::new(4, 0.5);
+            screen
+                .add_fasta(
+                    "phix",
+                    ">a\nGAGTTTTATCGCTTCC\n>b\nATGACGCAGAAGTT\n".as_bytes(),
+                )
+                .unwrap();
+            screen.add_reference("ecoli", b"AGCTTTTCATTCTGACTGCAACGGGCAATATG");
+            assert_eq!(screen.names(), ["phix", "ecoli"]);
+            assert_eq!(screen.classify(b"TTCTGACTGCAACGG"), Some("ecoli"));
+            assert_eq!(screen.classify(b"ACGCAGAAG"), Some("phix"));
+            assert_eq!(screen.classify(b"NNN"), None);
+        }
+    }
+}
+
+pub mod trim {
+    use std::fmt;
+
+    /// Adapter sequences read through into at the 3' end of short inserts.
+    pub const ILLUMINA_ADAPTERS: [(&str, &str); 3] = [
+        ("truseq", "AGATCGGAAGAGC"),
+        ("nextera", "CTGTCTCTTATACACATCT"),
+        ("smallrna", "TGGAATTCTCGG"),
+    ];
+
+    /// Why the 3' end of a read was trimmed.
+    #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+    pub enum Trimmed {
+        Adapter(String),
+        PolyG,
+    }
+
+    impl fmt::Display for Trimmed {
+        fn fmt(&self, dest: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Trimmed::Adapter(name) => write!(dest, "adapter {name}"),
+                Trimmed::PolyG => write!(dest, "poly-G"),
+            }
+        }
+    }
+
+    /// Trims adapters & poly-G tails from the 3' end of reads.
+    ///
+    /// An adapter is found anywhere in the read, or as a prefix of it of at
+    /// least `min_overlap` bases at the 3' end, with at most `max_error_rate`
+    /// mismatches per base compared.  A poly-G tail of at least `min_poly_g`
+    /// bases is trimmed first; 0 disables it.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Trimmer {
+        pub adapters: Vec<(String, Vec<u8>)>,
+        pub min_overlap: usize,
+        pub max_error_rate: f64,
+        pub min_poly_g: usize,
+    }
+
+    impl Default for Trimmer {
+        fn default() -> Self {
+            Trimmer {
+                adapters: Vec::new(),
+                min_overlap: 3,
+                max_error_rate: 0.1,
+                min_poly_g: 0,
+            }
+        }
+    }
+
+    /// Start of the G run at the 3' end of `seq`, allowing one other base per 8.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use myfq::trim::poly_g_start;
+    /// assert_eq!(poly_g_start(b"ACGTACGGGGGGGGGG"), 6);
+    /// assert_eq!(poly_g_start(b"ACGTAAGGGGTGGGGG"), 6);
+    /// assert_eq!(poly_g_start(b"ACGTACAT"), 8);
+    /// ```
+    pub fn poly_g_start(seq: &[u8]) -> usize {
+        let mut start = seq.len();
+        let mut others = 0;
+        for i in (0..seq.len()).rev() {
+            if seq[i] == b'G' {
+                start = i;
+            } else {
+                others += 1;
+                if others > (seq.len() - i).div_ceil(8) {
+                    break;
+                }
+            }
+        }
+        start
+    }
+
+    /// Start of the first occurrence of `adapter` in `seq`, whole or cut
+    /// off by the end of the read.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use myfq::trim::adapter_start;
+    /// let adapter = b"AGATCGGAAGAGC";
+    /// assert_eq!(adapter_start(b"GATTACAAGATCGGAAGAGCACAC", adapter, 3, 0.1), Some(7));
+    /// assert_eq!(adapter_start(b"GATTACAAGATC", adapter, 3, 0.1), Some(7));
+    /// assert_eq!(adapter_start(b"GATTACAAG", adapter, 3, 0.1), None);
+    /// ```
+    pub fn adapter_start(
+        seq: &[u8],
+        adapter: &[u8],
+        min_overlap: usize,
+        max_error_rate: f64,
+    ) -> Option<usize> {
+        let last = seq.len().checked_sub(min_overlap.max(1))?;
+        (0..=last).find(|&i| {
+            let len = adapter.len().min(seq.len() - i);
+            let mismatches = seq[i..i + len]
+                .iter()
+                .zip(adapter)
+                .filter(|(a, b)| !a.eq_ignore_ascii_case(b))
+                .count();
+            mismatches as f64 <= max_error_rate * len as f64
+        })
+    }
+
+    impl Trimmer {
+        /// Add the built-in Illumina adapters.
+        pub fn with_illumina_adapters(mut self) -> Self {
+            for (name, seq) in ILLUMINA_ADAPTERS {
+                self.adapters
+                    .push((name.to_string(), seq.as_bytes().to_vec()));
+            }
+            self
+        }
+
+        /// Length of `seq` to keep & why any more was trimmed; with more
+        /// than one reason the one applied last, the adapter, is given.
+        ///
+        /// # Examples
+        ///
+        /// ```
+        /// use myfq::trim::*;
+        /// let trimmer = Trimmer { min_poly_g: 8, ..Default::default() }.with_illumina_adapters();
+        /// let (keep, why) = trimmer.trim(b"GATTACAAGATCGGAAGAGCGGGGGGGGGGGG");
+        /// assert_eq!(keep, 7);
+        /// assert_eq!(why, Some(Trimmed::Adapter("truseq".to_string())));
+        /// assert_eq!(trimmer.trim(b"GATTACAGGGGGGGGGG").0, 7);
+        /// ```
+        pub fn trim(&self, seq: &[u8]) -> (usize, Option<Trimmed>) {
+            let mut keep = seq.len();
+            let mut why = None;
+            if self.min_poly_g > 0 {
+                let start = poly_g_start(seq);
+                if seq.len() - start >= self.min_poly_g {
+                    keep = start;
+                    why = Some(Trimmed::PolyG);
+                }
+            }
+            let adapter = self
+                .adapters
+                .iter()
+                .filter_map(|(name, adapter)| {
+                    adapter_start(&seq[..keep], adapter, self.min_overlap, self.max_error_rate)
+                        .map(|i| (i, name))
+                })
+                .min();
+            if let Some((start, name)) = adapter {
+                keep = start;
+                why = Some(Trimmed::Adapter(name.clone()));
+            }
+            (keep, why)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn adapter_with_mismatch() {
+            let seq = b"GATTACAAGATCGGTAGAGCACAC";
+            assert_eq!(adapter_start(seq, b"AGATCGGAAGAGC", 3, 0.1), Some(7));
+            assert_eq!(adapter_start(seq, b"AGATCGGAAGAGC", 3, 0.0), None);
+        }
+
+        #[test]
+        fn user_adapter_earliest_wins() {
+            let trimmer = Trimmer {
+                adapters: vec![("mine".to_string(), b"CCCCCC".to_vec())],
+                ..Default::default()
+            }
+            .with_illumina_adapters();
+            let (keep, why) = trimmer.trim(b"GATTACACCCCCCAGATCGGAAGAGC");
+            assert_eq!(keep, 7);
+            assert_eq!(why.unwrap().to_string(), "adapter mine");
+            assert_eq!(trimmer.trim(b"GATTACA"), (7, None));
+        }
+    }
+}
+
+pub mod report {
+    use crate::annotate::Annotation;
+    use crate::assign::Chimera;
+    use crate::merge::MergeStats;
+    use crate::trim::Trimmed;
+    use std::collections::{BTreeMap, BTreeSet};
+    use std::fmt;
+
+    /// Counts of reads by classification & sample, from annotated FASTQ descriptions.
+    #[derive(Debug, Default)]
+    pub struct DescriptionStats {
+        pub records: usize,
+        pub by_class: BTreeMap<String, usize>,
+        pub by_sample: BTreeMap<String, usize>,
+    }
+
+    impl DescriptionStats {
+        pub fn add(&mut self, annotation: &Annotation) {
+            self.records += 1;
+            *self
+                .by_class
+                .entry(annotation.classification().to_string())
+                .or_insert(0) += 1;
+            if let Some(sample) = &annotation.sample {
+                *self.by_sample.entry(sample.clone()).or_insert(0) += 1;
+            }
+        }
+    }
+
+    impl fmt::Display for DescriptionStats {
+        fn fmt(&self, dest: &mut fmt::Formatter) -> fmt::Result {
+            writeln!(dest, "records: {}", self.records)?;
+            for (class, count) in &self.by_class {
+                writeln!(dest, "{class}: {count}")?;
+            }
+            writeln!(dest, "samples:")?;
+            for (sample, count) in &self.by_sample {
+                writeln!(dest, "  {sample}: {count}")?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Read counts by sample (rows) & target (columns), written as a TSV matrix.
+    ///
+    /// Reads without a sample or target are counted under `.`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use myfq::report::CountMatrix;
+    /// let mut m = CountMatrix::default();
+    /// m.add(Some("S1"), Some("amp1"));
+    /// m.add(Some("S2"), Some("amp2"));
+    /// m.add(Some("S2"), Some("amp2"));
+    /// assert_eq!(m.to_string(), "sample\tamp1\tamp2\nS1\t1\t0\nS2\t0\t2\n");
+    /// ```
+    #[derive(Debug, Default)]
+    pub struct CountMatrix {
+        counts: BTreeMap<(String, String), usize>,
+    }
+
+    impl CountMatrix {
+        pub fn add(&mut self, sample: Option<&str>, target: Option<&str>) {
+            let key = (
+                sample.unwrap_or(".").to_string(),
+                target.unwrap_or(".").to_string(),
+            );
+            *self.counts.entry(key).or_insert(0) += 1;
+        }
+
+        pub fn get(&self, sample: &str, target: &str) -> usize {
+            self.counts
+                .get(&(sample.to_string(), target.to_string()))
+                .copied()
+                .unwrap_or(0)
+        }
+    }
+
+    impl fmt::Display for CountMatrix {
+        fn fmt(&self, dest: &mut fmt::Formatter) -> fmt::Result {
+            let samples: BTreeSet<&String> = self.counts.keys().map(|(s, _)| s).collect();
+            let targets: BTreeSet<&String> = self.counts.keys().map(|(_, t)| t).collect();
+            write!(dest, "sample")?;
+            for target in &targets {
+                write!(dest, "\t{target}")?;
+            }
+            writeln!(dest)?;
+            for sample in samples {
+                write!(dest, "{sample}")?;
+                for target in &targets {
+                    write!(dest, "\t{}", self.get(sample, target))?;
+                }
+                writeln!(dest)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Counts accumulated over a run, printed at the end.
+    ///
+    /// `config` holds the effective configuration of the run, as TOML.
+    #[derive(Debug, Default)]
+    pub struct Summary {
+        pub config: Option<String>,
+        pub records_read: usize,
+        pub merge: Option<MergeStats>,
+        pub trimmed: BTreeMap<Trimmed, usize>,
+        pub parse_errors: usize,
+        pub truncated: bool,
+        pub malformed: BTreeMap<String, usize>,
+        pub errors: usize,
+        pub ambiguous: usize,
+        pub chimeras: BTreeMap<Chimera, usize>,
+        pub structure_rejects: BTreeMap<String, usize>,
+        pub dimers: BTreeMap<String, usize>,
+        /// Reads outside the expected lengths of their primer pair, by sample.
+        pub length_rejects: BTreeMap<String, usize>,
+        pub contaminants: BTreeMap<String, usize>,
+        /// Reads split into sub-reads at internal primers, & the sub-reads made.
+        pub split: (usize, usize),
+        pub variant_hits: BTreeMap<(String, usize), usize>,
+        /// Reads on target & reads compared with an expected amplicon, by sample.
+        pub on_target: BTreeMap<String, (usize, usize)>,
+    }
+
+    impl Summary {
+        /// Count a record which failed its check for `reason`.
+        pub fn add_malformed(&mut self, reason: &str) {
+            *self.malformed.entry(reason.to_string()).or_insert(0) += 1;
+        }
+
+        /// Count an assigned read matched by variant `variant` of pooled primer `label`.
+        pub fn add_variant_hit(&mut self, label: &str, variant: usize) {
+            *self
+                .variant_hits
+                .entry((label.to_string(), variant))
+                .or_insert(0) += 1;
+        }
+
+        /// Count a read which does not fit the read structure for `reason`.
+        pub fn add_structure_reject(&mut self, reason: &str) {
+            *self
+                .structure_rejects
+                .entry(reason.to_string())
+                .or_insert(0) += 1;
+        }
+
+        /// Count a read of `sample` compared with its expected amplicon.
+        pub fn add_amplicon_check(&mut self, sample: Option<&str>, on_target: bool) {
+            let counts = self
+                .on_target
+                .entry(sample.unwrap_or(".").to_string())
+                .or_insert((0, 0));
+            counts.0 += usize::from(on_target);
+            counts.1 += 1;
+        }
+
+        /// Count a read whose 3' end was trimmed for `reason`.
+        pub fn add_trimmed(&mut self, reason: Trimmed) {
+            *self.trimmed.entry(reason).or_insert(0) += 1;
+        }
+
+        /// Count a read screened out as coming from the contaminant `name`.
+        pub fn add_contaminant(&mut self, name: &str) {
+            *self.contaminants.entry(name.to_string()).or_insert(0) += 1;
+        }
+
+        /// Count a read of `sample` outside the expected lengths for its primers.
+        pub fn add_length_reject(&mut self, sample: Option<&str>) {
+            *self
+                .length_rejects
+                .entry(sample.unwrap_or(".").to_string())
+                .or_insert(0) += 1;
+        }
+
+        /// Count a primer dimer of the pair `primers`, as `forward-reverse`.
+        pub fn add_dimer(&mut self, primers: &str) {
+            *self.dimers.entry(primers.to_string()).or_insert(0) += 1;
+        }
+
+        /// Count a read flagged as chimeric for `reason`.
+        pub fn add_chimera(&mut self, reason: Chimera) {
+            *self.chimeras.entry(reason).or_insert(0) += 1;
+        }
+    }
+
+    impl fmt::Display for Summary {
+        fn fmt(&self, dest: &mut fmt::Formatter) -> fmt::Result {
+            if let Some(config) = &self.config {
+                writeln!(dest, "configuration:")?;
+                for line in config.lines() {
+                    writeln!(dest, "  {line}")?;
+                }
+            }
+            writeln!(dest, "records read: {}", self.records_read)?;
+            if let Some(merge) = &self.merge {
+                write!(dest, "{merge}")?;
+            }
+            if !self.trimmed.is_empty() {
+                writeln!(dest, "trimmed: {}", self.trimmed.values().sum::<usize>())?;
+                for (reason, count) in &self.trimmed {
+                    writeln!(dest, "  {reason}: {count}")?;
+                }
+            }
+            if self.split.0 > 0 {
+                let (reads, pieces) = self.split;
+                writeln!(dest, "split reads: {reads} into {pieces}")?;
+            }
+            writeln!(dest, "parse errors: {}", self.parse_errors)?;
+            if self.truncated {
+                writeln!(dest, "input truncated")?;
+            }
+            writeln!(
+                dest,
+                "malformed: {}",
+                self.malformed.values().sum::<usize>()
+            )?;
+            for (reason, count) in &self.malformed {
+                writeln!(dest, "  {reason}: {count}")?;
+            }
+            if !self.contaminants.is_empty() {
+                let total: usize = self.contaminants.values().sum();
+                writeln!(dest, "contaminants: {total}")?;
+                for (name, count) in &self.contaminants {
+                    writeln!(dest, "  {name}: {count}")?;
+                }
+            }
+            writeln!(dest, "errors: {}", self.errors)?;
+            writeln!(dest, "ambiguous: {}", self.ambiguous)?;
+            writeln!(dest, "chimeras: {}", self.chimeras.values().sum::<usize>())?;
+            for (reason, count) in &self.chimeras {
+                writeln!(dest, "  {reason}: {count}")?;
+            }
+            if !self.dimers.is_empty() {
+                writeln!(
+                    dest,
+                    "primer dimers: {}",
+                    self.dimers.values().sum::<usize>()
+                )?;
+                for (primers, count) in &self.dimers {
+                    writeln!(dest, "  {primers}: {count}")?;
+                }
+            }
+            if !self.length_rejects.is_empty() {
+                let total: usize = self.length_rejects.values().sum();
+                writeln!(dest, "length rejects: {total}")?;
+                for (sample, count) in &self.length_rejects {
+                    writeln!(dest, "  {sample}: {count}")?;
+                }
+            }
+            if !self.variant_hits.is_empty() {
+                writeln!(dest, "primer variants:")?;
+                for ((label, variant), count) in &self.variant_hits {
+                    writeln!(dest, "  {label}[{variant}]: {count}")?;
+                }
+            }
+            if !self.structure_rejects.is_empty() {
+                let total: usize = self.structure_rejects.values().sum();
+                writeln!(dest, "structure rejects: {total}")?;
+                for (reason, count) in &self.structure_rejects {
+                    writeln!(dest, "  {reason}: {count}")?;
+                }
+            }
+            if !self.on_target.is_empty() {
+                writeln!(dest, "on target:")?;
+                for (sample, (on, checked)) in &self.on_target {
+                    let rate = 100.0 * *on as f64 / *checked as f64;
+                    writeln!(dest, "  {sample}: {on}/{checked} ({rate:.1}%)")?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn description_stats() {
+            let mut stats = DescriptionStats::default();
+            stats.add(&Annotation::parse("sample=S1 primers=a-b"));
+            stats.add(&Annotation::parse("sample=S1 primers=a-b"));
+            stats.add(&Annotation::parse("sample=. primers=invalid"));
+            assert_eq!(stats.records, 3);
+            assert_eq!(stats.by_class["assigned"], 2);
+            assert_eq!(stats.by_class["invalid"], 1);
+            assert_eq!(stats.by_sample["S1"], 2);
+        }
+
+        #[test]
+        fn summary_counts_chimeras() {
+            let mut summary = Summary::default();
+            summary.add_chimera(Chimera::InternalPrimer);
+            summary.add_chimera(Chimera::InternalPrimer);
+            summary.add_chimera(Chimera::RepeatedForward);
+            let s = summary.to_string();
+            assert!(s.contains("chimeras: 3"));
+            assert!(s.contains("  internal: 2"));
+            assert!(s.contains("  repeated: 1"));
+        }
+
+        #[test]
+        fn summary_on_target_rates() {
+            let mut summary = Summary::default();
+            summary.add_amplicon_check(Some("S1"), true);
+            summary.add_amplicon_check(Some("S1"), false);
+            summary.add_amplicon_check(None, true);
+            let s = summary.to_string();
+            assert!(s.contains("  S1: 1/2 (50.0%)"));
+            assert!(s.contains("  .: 1/1 (100.0%)"));
+        }
+    }
+}
@@ -1,98 +1,1371 @@
 use bio::io::fastq;
-use clap::Parser;
-use itertools::Itertools;
-use myfq::samples::read_wide_table;
-use std::collections::HashMap;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use myfq::samples::{read_panel, read_wide_table, Panel, PrimerPair, SamplesTable};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{self, prelude::*, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
-use myfq::primers::{read_primer_table, Direction, Primer};
+use bio::alphabets::dna;
+use myfq::amplicon::{read_references, AmpliconMatch, References};
+use myfq::annotate::{Annotation, Field};
+use myfq::assign::{
+    clip_range, detect_chimera, insert_length, split_points, Candidate, Chimera, Orientation,
+    Resolution, Resolver,
+};
+use myfq::assignments::{self, Assignment, WriteAssignments};
+use myfq::asv::{self, Dereplicator};
+use myfq::config;
+use myfq::consensus::Pileup;
+use myfq::input::{self, OnError};
+use myfq::merge::{MergeStats, Merger};
+use myfq::primers::{read_primer_table, Direction, Primer, PrimerHit, QualityMatch};
+use myfq::report::{CountMatrix, DescriptionStats, Summary};
+use myfq::screen::KmerScreen;
+use myfq::structure::{ReadStructure, StructureError};
+use myfq::trim::Trimmer;
+use myfq::umi::{UmiCounts, UmiLayout};
+use myfq::unaligned::UnalignedWriter;
+use serde::{Deserialize, Serialize};
+
+#[derive(ValueEnum, Clone, Copy, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    Fastq,
+    Sam,
+    Bam,
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
-struct Args {
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Assign reads from a FASTQ on stdin to samples by their primers
+    Demux(Box<DemuxArgs>),
+    /// Check a primers file and a samples file for consistency
+    Validate {
+        /// Primers file
+        #[arg(short, long)]
+        primers: PathBuf,
+        /// Samples file
+        #[arg(short, long)]
+        samples: PathBuf,
+    },
+    /// Summarise an annotated FASTQ written by `demux`
+    Stats {
+        /// Annotated FASTQ; read from stdin if not given
+        input: Option<PathBuf>,
+    },
+    /// Call a consensus sequence per sample from an annotated FASTQ written by `demux`
+    Consensus {
+        /// Annotated FASTQ, best clipped to the inserts; read from stdin if not given
+        input: Option<PathBuf>,
+        /// Minimum fraction of reads for a base to be called; more than one
+        /// base is called as an IUPAC code
+        #[arg(long, default_value_t = 0.2)]
+        min_fraction: f64,
+        /// Write the depth & fraction of reads supporting each position (TSV)
+        #[arg(long)]
+        report: Option<PathBuf>,
+    },
+    /// List the primers in a primers file, with their reverse complements
+    Primers {
+        /// Primers file
+        #[arg(short, long)]
+        primers: PathBuf,
+    },
+    /// Print a samples table
+    Layout {
+        /// Samples file
+        #[arg(short, long)]
+        samples: PathBuf,
+        /// One line per sample instead of the forward x reverse grid
+        #[arg(long)]
+        narrow: bool,
+    },
+}
+
+/// Options for `demux`.
+///
+/// A TOML file given with `--config` may set any of these by field name;
+/// options given on the command line take precedence.
+#[derive(Args, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DemuxArgs {
+    /// Run configuration file (TOML) with the same keys as these options
+    #[arg(long)]
+    #[serde(skip)]
+    config: Option<PathBuf>,
     /// Primers file
     #[arg(short, long)]
-    primers: std::path::PathBuf,
+    primers: Option<PathBuf>,
     /// Samples file
     #[arg(short, long)]
-    samples: std::path::PathBuf,
+    samples: Option<PathBuf>,
+    /// Maximum quality-weighted mismatch penalty for a primer hit
+    #[arg(long, default_value_t = 0.0)]
+    max_penalty: f64,
+    /// Treat read bases below this Phred quality as wildcards when matching primers
+    #[arg(long, default_value_t = 0)]
+    min_quality: u8,
+    /// Score margin by which the best primer must beat the runner-up
+    #[arg(long, default_value_t = 0.5)]
+    margin: f64,
+    /// Score penalty per base between a primer hit and the end of the read
+    #[arg(long, default_value_t = 0.01)]
+    position_weight: f64,
+    /// Score penalty for a primer hit without its barcode
+    #[arg(long, default_value_t = 1.0)]
+    barcode_weight: f64,
+    /// Primer hits further than this from both ends of the read mark it as a chimera
+    #[arg(long, default_value_t = 30)]
+    end_window: usize,
+    /// Fields written to the FASTQ description; spacer is added when the primers
+    /// have spacers, target with --panel, umi with --umi-length, & strand with
+    /// --reorient or --long-read
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "sample,primers,orient,hits"
+    )]
+    fields: Vec<Field>,
+    /// Output format; SAM & BAM records are unmapped, tagged with sample & primers
+    #[arg(long, value_enum, default_value_t = Format::Fastq)]
+    format: Format,
+    /// Write one row per read explaining its assignment (TSV, or Parquet for *.parquet)
+    #[arg(long)]
+    assignments: Option<PathBuf>,
+    /// What to do with a FASTQ record which cannot be read: skip or fail
+    #[arg(long, default_value = "skip")]
+    on_error: OnError,
+    /// Expected read layout, e.g. "[spacer 0-7][barcode 8][primer][insert][rc primer]"
+    #[arg(long)]
+    structure: Option<String>,
+    /// Clip assigned reads to the bases between the primers, removing any spacer;
+    /// positions in the hits & segments fields still refer to the unclipped read
+    #[arg(long)]
+    clip: bool,
+    /// Panel file mapping primer pairs to amplicon targets & expected lengths;
+    /// lengths can only be given here, not in the samples file
+    #[arg(long)]
+    panel: Option<PathBuf>,
+    /// Write a sample x target matrix of assigned read counts (TSV); targets are
+    /// looked up by the primer pair of each read
+    #[arg(long)]
+    counts: Option<PathBuf>,
+    /// FASTA of expected amplicons, named by target or as forward-reverse primers
+    #[arg(long)]
+    amplicons: Option<PathBuf>,
+    /// Minimum identity to the expected amplicon for a read to be on target
+    #[arg(long, default_value_t = 0.9)]
+    min_identity: f64,
+    /// Second reads of pairs, in the same order as the reads on stdin;
+    /// overlapping pairs are merged before assignment
+    #[arg(long)]
+    mate: Option<PathBuf>,
+    /// Minimum overlap for merging a pair
+    #[arg(long, default_value_t = 10)]
+    min_overlap: usize,
+    /// Maximum fraction of mismatched bases in the overlap of a merged pair
+    #[arg(long, default_value_t = 0.1)]
+    max_mismatch_rate: f64,
+    /// Write pairs which cannot be merged to PREFIX_1.fastq & PREFIX_2.fastq
+    #[arg(long, value_name = "PREFIX")]
+    unmerged: Option<PathBuf>,
+    /// Dereplicate the inserts of assigned reads, writing a sample x sequence
+    /// matrix to PREFIX.tsv & the sequences to PREFIX.fasta
+    #[arg(long, value_name = "PREFIX")]
+    asv: Option<PathBuf>,
+    /// Minimum number of reads, over all samples, for a sequence to be written with --asv
+    #[arg(long, default_value_t = 1)]
+    min_abundance: usize,
+    /// Length of the UMI; the UMI is added to the description of assigned reads.
+    /// UMIs are only counted, with --molecules: reads are not deduplicated
+    #[arg(long)]
+    umi_length: Option<usize>,
+    /// Start of the UMI relative to the start of the primer at the 5' end of
+    /// the read; negative for a UMI before the primer
+    #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+    umi_offset: isize,
+    /// Maximum mismatches between UMIs of one molecule
+    #[arg(long, default_value_t = 1)]
+    umi_distance: usize,
+    /// Write reads, UMIs & molecules per sample, after clustering UMIs (TSV)
+    #[arg(long)]
+    molecules: Option<PathBuf>,
+    /// Trim the common Illumina adapters from the 3' end of reads before matching primers
+    #[arg(long)]
+    trim_adapters: bool,
+    /// Another adapter to trim, as SEQUENCE or NAME=SEQUENCE; may be repeated
+    #[arg(long = "adapter", value_name = "ADAPTER")]
+    adapters: Vec<String>,
+    /// Minimum bases of an adapter at the 3' end of a read to trim
+    #[arg(long, default_value_t = 3)]
+    min_adapter_overlap: usize,
+    /// Trim 3' poly-G tails of at least this length, as from two-colour chemistry
+    #[arg(long)]
+    poly_g: Option<usize>,
+    /// Classify reads with fewer bases than this between the primers as primer dimers
+    #[arg(long)]
+    min_insert: Option<usize>,
+    /// Leave primer dimers out of the reads & counts written per sample
+    #[arg(long)]
+    exclude_dimers: bool,
+    /// FASTA of a contaminant such as PhiX, as PATH or NAME=PATH; reads sharing
+    /// enough k-mers with it are not assigned.  May be repeated
+    #[arg(long = "contaminant", value_name = "REFERENCE")]
+    contaminants: Vec<String>,
+    /// K-mer length for the contaminant screen, at most 32
+    #[arg(long, default_value_t = 25)]
+    kmer: usize,
+    /// Minimum fraction of a read's k-mers found in a contaminant to screen it out
+    #[arg(long, default_value_t = 0.5)]
+    contaminant_fraction: f64,
+    /// Long, error-prone reads (e.g. Nanopore): search for primers with edit
+    /// distance near the ends only; implies --reorient
+    #[arg(long)]
+    long_read: bool,
+    /// Bases at each end of a long read to search for primers
+    #[arg(long, default_value_t = 150)]
+    window: usize,
+    /// Maximum edits per primer base for a primer hit in a long read
+    #[arg(long, default_value_t = 0.15)]
+    max_error_rate: f64,
+    /// Split reads at internal primers into sub-reads ID_1, ID_2, ..., each assigned separately
+    #[arg(long)]
+    split: bool,
+    /// Write assigned reads in forward primer orientation, reverse complementing
+    /// reads from the other strand; the strand as sequenced is added to the description
+    #[arg(long)]
+    reorient: bool,
 }
 
-fn main() {
-    let args = Args::parse();
-    eprintln!("{:?}", args);
+fn main() -> ExitCode {
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    match cli.command {
+        Command::Demux(args) => {
+            let demux_matches = matches.subcommand_matches("demux").expect("demux matches");
+            match apply_config(*args, demux_matches) {
+                Ok(args) => demux(args),
+                Err(e) => {
+                    eprintln!("Invalid configuration: {e}");
+                    ExitCode::FAILURE
+                }
+            }
+        }
+        Command::Validate { primers, samples } => validate(&primers, &samples),
+        Command::Stats { input } => stats(input.as_deref()),
+        Command::Consensus {
+            input,
+            min_fraction,
+            report,
+        } => consensus(input.as_deref(), min_fraction, report.as_deref()),
+        Command::Primers { primers } => list_primers(&primers),
+        Command::Layout { samples, narrow } => layout(&samples, narrow),
+    }
+}
 
-    let mut primer_file = File::open(args.primers).expect("Unable to open primers file.");
-    let primer_table = read_primer_table(&mut primer_file).expect("Invalid primers file.");
+/// Fill in options from the `--config` file which were not given on the command line.
+fn apply_config(
+    args: DemuxArgs,
+    matches: &ArgMatches,
+) -> Result<DemuxArgs, Box<dyn std::error::Error>> {
+    let Some(path) = args.config.clone() else {
+        return Ok(args);
+    };
+    let file = std::fs::read_to_string(&path)?;
+    let mut merged = config::overlay(&args, &file, |key| {
+        matches.ids().any(|id| id.as_str() == key)
+            && matches!(matches.value_source(key), Some(ValueSource::CommandLine))
+    })?;
+    merged.config = Some(path);
+    Ok(merged)
+}
 
-    let samples_file = Box::new(BufReader::new(
-        File::open(args.samples).expect("Unable to open samples file."),
-    )) as Box<dyn BufRead>;
-    let samples_table = read_wide_table(samples_file).expect("Unable to read samples table.");
+fn load_primers(path: &Path) -> Result<Vec<Primer>, Box<dyn std::error::Error>> {
+    let mut primer_file = File::open(path)?;
+    read_primer_table(&mut primer_file)
+}
+
+fn load_samples(path: &Path) -> Result<SamplesTable, Box<dyn std::error::Error>> {
+    let samples_file = Box::new(BufReader::new(File::open(path)?)) as Box<dyn BufRead>;
+    Ok(read_wide_table(samples_file)?)
+}
 
-    let mut records = fastq::Reader::new(io::stdin()).records();
-    let mut writer = fastq::Writer::new(io::stdout());
+fn load_panel(path: &Path) -> Result<Panel, Box<dyn std::error::Error>> {
+    let panel_file = Box::new(BufReader::new(File::open(path)?)) as Box<dyn BufRead>;
+    Ok(read_panel(panel_file)?)
+}
 
-    let mut records_read = 0;
-    let mut records_error = 0;
+fn load_amplicons(path: &Path) -> Result<References, Box<dyn std::error::Error>> {
+    Ok(read_references(File::open(path)?)?)
+}
 
-    let mut matches = HashMap::<String, bool>::with_capacity(primer_table.len() * 2);
-    let mut forward_primers = Vec::<&Primer>::with_capacity(primer_table.len());
-    let mut reverse_primers = Vec::<&Primer>::with_capacity(primer_table.len());
-    for p in &primer_table {
-        matches.insert(p.label().to_owned(), false);
-        matches.insert(p.label_rc().to_owned(), false);
+fn validate(primers: &Path, samples: &Path) -> ExitCode {
+    let primer_table = match load_primers(primers) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("Invalid primers file: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let samples_table = match load_samples(samples) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("Invalid samples file: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let problems = samples_table.check_primers(&primer_table);
+    for problem in &problems {
+        println!("{problem}");
+    }
+    println!(
+        "{} primers, {} samples, {} problems",
+        primer_table.len(),
+        samples_table.iter().count(),
+        problems.len()
+    );
+    if problems.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Open a FASTQ file, or stdin if no path is given.
+fn open_fastq(input: Option<&Path>) -> io::Result<Box<dyn Read>> {
+    Ok(match input {
+        Some(path) => Box::new(File::open(path)?),
+        None => Box::new(io::stdin()),
+    })
+}
+
+fn stats(input: Option<&Path>) -> ExitCode {
+    let reader = match open_fastq(input) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Unable to open FASTQ file: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut stats = DescriptionStats::default();
+    for record in fastq::Reader::new(reader).records() {
+        let record = match record {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!("Invalid FASTQ record: {e}");
+                return ExitCode::FAILURE;
+            }
+        };
+        stats.add(&Annotation::parse(record.desc().unwrap_or_default()));
     }
+    print!("{stats}");
+    ExitCode::SUCCESS
+}
 
-    while let Some(Ok(record)) = records.next() {
-        forward_primers.clear();
-        reverse_primers.clear();
-        records_read += 1;
-        let check = record.check();
-        if check.is_err() {
-            records_error += 1;
+fn consensus(input: Option<&Path>, min_fraction: f64, report: Option<&Path>) -> ExitCode {
+    let reader = match open_fastq(input) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("Unable to open FASTQ file: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut pileups = BTreeMap::<(String, String), Pileup>::new();
+    for record in fastq::Reader::new(reader).records() {
+        let record = match record {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!("Invalid FASTQ record: {e}");
+                return ExitCode::FAILURE;
+            }
+        };
+        let annotation = Annotation::parse(record.desc().unwrap_or_default());
+        if annotation.classification() != "assigned" {
+            continue;
+        }
+        let reversed = annotation.written_reversed();
+        let Some(sample) = annotation.sample else {
             continue;
+        };
+        let pileup = pileups.entry((sample, annotation.primers)).or_default();
+        if reversed {
+            pileup.add(&dna::revcomp(record.seq()));
+        } else {
+            pileup.add(record.seq());
+        }
+    }
+
+    match write_consensus(&pileups, min_fraction, report) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Unable to write report: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Print the consensus of each pileup as FASTA, & write the support for each
+/// position to the `report` file if given.
+fn write_consensus(
+    pileups: &BTreeMap<(String, String), Pileup>,
+    min_fraction: f64,
+    report: Option<&Path>,
+) -> io::Result<()> {
+    let mut report = match report {
+        Some(path) => {
+            let mut w = io::BufWriter::new(File::create(path)?);
+            writeln!(w, "sample\tprimers\tposition\tbase\tdepth\tsupport")?;
+            Some(w)
+        }
+        None => None,
+    };
+    for ((sample, primers), pileup) in pileups {
+        let Some(consensus) = pileup.consensus(min_fraction) else {
+            continue;
+        };
+        println!(">{sample} primers={primers} reads={}", consensus.reads);
+        println!("{}", String::from_utf8_lossy(&consensus.seq()));
+        if let Some(w) = &mut report {
+            for (i, p) in consensus.positions.iter().enumerate() {
+                writeln!(
+                    w,
+                    "{sample}\t{primers}\t{}\t{}\t{}\t{:.3}",
+                    i + 1,
+                    p.base as char,
+                    p.depth,
+                    p.support
+                )?;
+            }
+        }
+    }
+    if let Some(w) = &mut report {
+        w.flush()?;
+    }
+    Ok(())
+}
+
+fn list_primers(primers: &Path) -> ExitCode {
+    let primer_table = match load_primers(primers) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("Invalid primers file: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    println!("label\tvariant\tdirection\tsequence\tlabel_rc\tsequence_rc\tbarcode");
+    for p in &primer_table {
+        println!(
+            "{}\t{}\t{:?}\t{}\t{}\t{}\t{}",
+            p.label(),
+            p.variant(),
+            p.direction(),
+            String::from_utf8_lossy(p.sequence()),
+            p.label_rc(),
+            String::from_utf8_lossy(p.sequence_rc()),
+            String::from_utf8_lossy(p.barcode()),
+        );
+    }
+    ExitCode::SUCCESS
+}
+
+fn layout(samples: &Path, narrow: bool) -> ExitCode {
+    let samples_table = match load_samples(samples) {
+        Ok(t) => t,
+        Err(e) => {
+            eprintln!("Invalid samples file: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+    if narrow {
+        print!("{:#}", samples_table);
+    } else {
+        print!("{}", samples_table);
+    }
+    ExitCode::SUCCESS
+}
+
+/// Record the sample, orientation & primer pair for a read with a unique
+/// forward & reverse primer, returning the `primers` description value.
+fn assign(
+    annotation: &mut Annotation,
+    samples: &SamplesTable,
+    f: &Candidate,
+    r: &Candidate,
+) -> String {
+    annotation.sample = samples.get_sample_name_by_names(f.primer.label(), r.primer.label());
+    annotation.orient = Some(f.orientation());
+    annotation.spacer = f.spacer().or(r.spacer());
+    annotation.pair = Some(PrimerPair {
+        forward: f.primer.label().to_string(),
+        reverse: r.primer.label().to_string(),
+    });
+    format!("{}-{}", f.primer.label(), r.primer.label())
+}
+
+/// Create the two FASTQ files for unmerged pairs, `PREFIX_1.fastq` & `PREFIX_2.fastq`.
+fn unmerged_writers(prefix: &Path) -> io::Result<[fastq::Writer<File>; 2]> {
+    let path = |n: usize| {
+        let mut name = prefix.as_os_str().to_owned();
+        name.push(format!("_{n}.fastq"));
+        PathBuf::from(name)
+    };
+    Ok([
+        fastq::Writer::to_file(path(1))?,
+        fastq::Writer::to_file(path(2))?,
+    ])
+}
+
+/// Write the ASVs with at least `min_abundance` reads to `PREFIX.tsv` & `PREFIX.fasta`.
+fn write_asvs(
+    prefix: &Path,
+    dereplicator: &Dereplicator,
+    samples: &SamplesTable,
+    min_abundance: usize,
+) -> io::Result<()> {
+    let path = |ext: &str| {
+        let mut name = prefix.as_os_str().to_owned();
+        name.push(ext);
+        PathBuf::from(name)
+    };
+    let asvs = dereplicator.asvs(min_abundance);
+    let names = samples.iter().map(|(_, s)| s.name().to_string()).collect();
+    asv::write_table(
+        io::BufWriter::new(File::create(path(".tsv"))?),
+        &names,
+        &asvs,
+    )?;
+    asv::write_fasta(io::BufWriter::new(File::create(path(".fasta"))?), &asvs)
+}
+
+/// Load the contaminant references, named as given or by their file name.
+fn load_screen(args: &DemuxArgs) -> Result<KmerScreen, Box<dyn std::error::Error>> {
+    if !(1..=32).contains(&args.kmer) {
+        return Err(format!("k-mer length {} is not 1 to 32", args.kmer).into());
+    }
+    let mut screen = KmerScreen::new(args.kmer, args.contaminant_fraction);
+    for reference in &args.contaminants {
+        let (name, path) = match reference.split_once('=') {
+            Some((name, path)) => (name.to_string(), Path::new(path)),
+            None => {
+                let path = Path::new(reference);
+                let name = path.file_stem().unwrap_or(path.as_os_str());
+                (name.to_string_lossy().into_owned(), path)
+            }
+        };
+        screen.add_fasta(&name, File::open(path)?)?;
+    }
+    Ok(screen)
+}
+
+/// Every hit of `p` & its reverse complement in a read, exact or weighted by quality.
+fn find_hits(
+    p: &Primer,
+    record: &fastq::Record,
+    quality_match: Option<&QualityMatch>,
+) -> (Vec<PrimerHit>, Vec<PrimerHit>) {
+    match quality_match {
+        Some(qm) => (
+            p.find_in_with_quality(record.seq(), record.qual(), qm),
+            p.find_in_rc_with_quality(record.seq(), record.qual(), qm),
+        ),
+        None => (p.find_in(record.seq()), p.find_in_rc(record.seq())),
+    }
+}
+
+/// Edits allowed in a long-read hit of `p`.
+fn max_edits(p: &Primer, max_error_rate: f64) -> usize {
+    (max_error_rate * p.sequence().len() as f64).ceil() as usize
+}
+
+/// Hits of each primer & its reverse complement anywhere in a read, in the
+/// order of `primers`, for finding split points.
+fn whole_read_hits(
+    record: &fastq::Record,
+    primers: &[Primer],
+    args: &DemuxArgs,
+    quality_match: Option<&QualityMatch>,
+) -> Vec<(Vec<PrimerHit>, Vec<PrimerHit>)> {
+    primers
+        .iter()
+        .map(|p| {
+            if args.long_read {
+                let max_edits = max_edits(p, args.max_error_rate);
+                (
+                    p.find_all_with_edits(record.seq(), max_edits),
+                    p.find_all_rc_with_edits(record.seq(), max_edits),
+                )
+            } else {
+                find_hits(p, record, quality_match)
+            }
+        })
+        .collect()
+}
+
+/// Split a read at internal primers, given the `hits` of each of `primers`,
+/// into sub-reads `ID_1`, `ID_2`, ...; a read without internal primers is
+/// returned whole.
+fn split_record(
+    record: fastq::Record,
+    primers: &[Primer],
+    hits: &[(Vec<PrimerHit>, Vec<PrimerHit>)],
+) -> Vec<fastq::Record> {
+    let mut candidates = Vec::new();
+    for (p, (hits, hits_rc)) in primers.iter().zip(hits) {
+        for (hits, rc) in [(hits, false), (hits_rc, true)] {
+            candidates.extend(hits.iter().map(|&hit| Candidate { primer: p, hit, rc }));
         }
+    }
+    let points = split_points(&candidates);
+    if points.is_empty() {
+        return vec![record];
+    }
+    let bounds: Vec<usize> = std::iter::once(0)
+        .chain(points)
+        .chain(std::iter::once(record.seq().len()))
+        .collect();
+    bounds
+        .windows(2)
+        .enumerate()
+        .map(|(i, w)| {
+            fastq::Record::with_attrs(
+                &format!("{}_{}", record.id(), i + 1),
+                record.desc(),
+                &record.seq()[w[0]..w[1]],
+                &record.qual()[w[0]..w[1]],
+            )
+        })
+        .collect()
+}
 
-        for p in &primer_table {
-            matches.insert(p.label().to_owned(), p.is_found_in(record.seq()));
-            matches.insert(p.label_rc().to_owned(), p.is_found_in_rc(record.seq()));
+/// The best hits of `p` near the start of a long read & of its reverse
+/// complement near the end.
+fn end_hits(
+    p: &Primer,
+    record: &fastq::Record,
+    args: &DemuxArgs,
+) -> (Vec<PrimerHit>, Vec<PrimerHit>) {
+    let max_edits = max_edits(p, args.max_error_rate);
+    (
+        Vec::from_iter(p.find_near_start(record.seq(), args.window, max_edits)),
+        Vec::from_iter(p.find_rc_near_end(record.seq(), args.window, max_edits)),
+    )
+}
+
+/// The long-read hits of a primer: the best of `hits` within `window` bases
+/// of the start of a read of length `len`, & the best of `hits_rc` within
+/// `window` bases of its end.  A primer belongs at one end only, so only the
+/// better of the two is kept.
+fn near_ends(
+    hits: Vec<PrimerHit>,
+    hits_rc: Vec<PrimerHit>,
+    len: usize,
+    window: usize,
+) -> (Vec<PrimerHit>, Vec<PrimerHit>) {
+    let best = |hits: Vec<PrimerHit>, near: &dyn Fn(&PrimerHit) -> bool| {
+        hits.into_iter()
+            .filter(near)
+            .min_by(|a, b| a.penalty.total_cmp(&b.penalty).then(a.start.cmp(&b.start)))
+    };
+    let hit = best(hits, &|h| h.end <= window);
+    let hit_rc = best(hits_rc, &|h| h.start >= len.saturating_sub(window));
+    match (hit, hit_rc) {
+        (Some(h), Some(h_rc)) if h.penalty < h_rc.penalty => (vec![h], vec![]),
+        (Some(h), Some(h_rc)) if h_rc.penalty < h.penalty => (vec![], vec![h_rc]),
+        _ => (Vec::from_iter(hit), Vec::from_iter(hit_rc)),
+    }
+}
 
-            if p.is_found_in(record.seq()) | p.is_found_in_rc(record.seq()) {
-                match p.direction() {
-                    Direction::Forward => forward_primers.push(p),
-                    Direction::Reverse => reverse_primers.push(p),
+fn make_trimmer(args: &DemuxArgs) -> Trimmer {
+    let mut trimmer = Trimmer {
+        min_overlap: args.min_adapter_overlap,
+        min_poly_g: args.poly_g.unwrap_or(0),
+        ..Default::default()
+    };
+    for (i, adapter) in args.adapters.iter().enumerate() {
+        let (name, seq) = match adapter.split_once('=') {
+            Some((name, seq)) => (name.to_string(), seq),
+            None => (format!("adapter{}", i + 1), adapter.as_str()),
+        };
+        trimmer
+            .adapters
+            .push((name, seq.to_ascii_uppercase().into_bytes()));
+    }
+    if args.trim_adapters {
+        trimmer = trimmer.with_illumina_adapters();
+    }
+    trimmer
+}
+
+/// Read IDs of mates may differ by a `/1` & `/2` suffix.
+fn mate_ids_match(id1: &str, id2: &str) -> bool {
+    let base = |id: &'_ str| {
+        id.strip_suffix("/1")
+            .or_else(|| id.strip_suffix("/2"))
+            .unwrap_or(id)
+            .to_string()
+    };
+    base(id1) == base(id2)
+}
+
+/// Compare the read from primer to primer, in forward primer orientation,
+/// with its expected amplicon.
+fn check_amplicon(
+    seq: &[u8],
+    f: &Candidate,
+    r: &Candidate,
+    expected: &[u8],
+    min_identity: f64,
+) -> AmpliconMatch {
+    let [five, three] = if f.rc { [r, f] } else { [f, r] };
+    let amplicon = &seq[five.hit.start..three.hit.end.max(five.hit.start)];
+    let insert = clip_range(seq.len(), &[*f, *r]).len();
+    if f.rc {
+        AmpliconMatch::new(&dna::revcomp(amplicon), insert, expected, min_identity)
+    } else {
+        AmpliconMatch::new(amplicon, insert, expected, min_identity)
+    }
+}
+
+/// Fit the read structure to a read with a unique forward & reverse primer.
+///
+/// The primer found as itself anchors `[primer]` and the one found as its
+/// reverse complement anchors `[rc primer]`, whatever their direction.
+fn locate_segments(
+    structure: &ReadStructure,
+    read_len: usize,
+    f: &Candidate,
+    r: &Candidate,
+) -> Result<Vec<(String, usize, usize)>, StructureError> {
+    let anchor = |rc: bool| {
+        [f, r]
+            .into_iter()
+            .find(|c| c.rc == rc)
+            .map(|c| (c.hit.start, c.hit.end))
+    };
+    let segments = structure.apply(read_len, anchor(false), anchor(true))?;
+    Ok(segments
+        .into_iter()
+        .map(|e| (e.name, e.start, e.end))
+        .collect())
+}
+
+/// The primers, tables & options which classify the reads of a `demux` run.
+struct Classifier<'a> {
+    args: &'a DemuxArgs,
+    primers: &'a [Primer],
+    samples: &'a SamplesTable,
+    panel: Option<&'a Panel>,
+    references: Option<&'a References>,
+    screen: Option<&'a KmerScreen>,
+    structure: Option<&'a ReadStructure>,
+    umi_layout: Option<UmiLayout>,
+    quality_match: Option<QualityMatch>,
+    resolver: Resolver,
+    end_window: usize,
+}
+
+/// A read's annotation, & the forward & reverse primers it was resolved to.
+struct Classified<'a> {
+    annotation: Annotation,
+    forward: Resolution<'a>,
+    reverse: Resolution<'a>,
+}
+
+impl<'a> Classifier<'a> {
+    /// Annotate a read by its primers, adding it to the `summary`.  An unsplit
+    /// read keeps the `hits` found while looking for split points.
+    fn classify(
+        &self,
+        record: &fastq::Record,
+        hits: Option<Vec<(Vec<PrimerHit>, Vec<PrimerHit>)>>,
+        summary: &mut Summary,
+    ) -> Classified<'a> {
+        let contaminant = self.screen.and_then(|s| s.classify(record.seq()));
+        let (forward_primers, reverse_primers) = match contaminant {
+            Some(_) => Default::default(),
+            None => self.candidates(record, hits),
+        };
+        let forward = self.resolver.resolve(&forward_primers, record.seq());
+        let reverse = self.resolver.resolve(&reverse_primers, record.seq());
+        let dimer = match (&forward, &reverse) {
+            (Resolution::Unique(f), Resolution::Unique(r)) => self.is_dimer(f, r),
+            _ => false,
+        };
+        // a dimer read through into the adapter has its rc primer away from the end
+        let chimera = detect_chimera(
+            &forward_primers,
+            &reverse_primers,
+            record.seq().len(),
+            self.end_window,
+        )
+        .filter(|&c| !(dimer && c == Chimera::InternalPrimer));
+        let mut annotation = Annotation::default();
+        annotation.add_hits(&forward_primers);
+        annotation.add_hits(&reverse_primers);
+        annotation.primers = if let Some(name) = contaminant {
+            summary.add_contaminant(name);
+            format!("contaminant:{name}")
+        } else if let Some(reason) = chimera {
+            summary.add_chimera(reason);
+            format!("chimera:{reason}")
+        } else {
+            match (&forward, &reverse) {
+                (Resolution::Unique(f), Resolution::Unique(r)) => {
+                    self.classify_pair(record, f, r, &mut annotation, summary)
                 }
+                (Resolution::Ambiguous(..), _) | (_, Resolution::Ambiguous(..)) => {
+                    summary.ambiguous += 1;
+                    let mut s = "ambiguous".to_string();
+                    for resolution in [&forward, &reverse] {
+                        if let Resolution::Ambiguous(a, b) = resolution {
+                            s.push_str(&format!(":{}|{}", a.hit_label(), b.hit_label()));
+                        }
+                    }
+                    s
+                }
+                _ => {
+                    summary.errors += 1;
+                    "invalid".to_string()
+                }
+            }
+        };
+        Classified {
+            annotation,
+            forward,
+            reverse,
+        }
+    }
+
+    /// The forward & reverse primer candidates in a read, from the `hits` of
+    /// each primer if given.
+    fn candidates(
+        &self,
+        record: &fastq::Record,
+        hits: Option<Vec<(Vec<PrimerHit>, Vec<PrimerHit>)>>,
+    ) -> (Vec<Candidate<'a>>, Vec<Candidate<'a>>) {
+        let mut forward_primers = Vec::with_capacity(self.primers.len());
+        let mut reverse_primers = Vec::with_capacity(self.primers.len());
+        let mut read_hits = hits.map(Vec::into_iter);
+        for p in self.primers {
+            let (hits, hits_rc) = match read_hits.as_mut().and_then(Iterator::next) {
+                Some(hits) => hits,
+                None if self.args.long_read => end_hits(p, record, self.args),
+                None => find_hits(p, record, self.quality_match.as_ref()),
+            };
+            let (hits, hits_rc) = if self.args.long_read {
+                near_ends(hits, hits_rc, record.seq().len(), self.args.window)
+            } else {
+                (hits, hits_rc)
+            };
+
+            let candidates = match p.direction() {
+                Direction::Forward => &mut forward_primers,
+                Direction::Reverse => &mut reverse_primers,
+            };
+            for (hits, rc) in [(hits, false), (hits_rc, true)] {
+                candidates.extend(hits.into_iter().map(|hit| Candidate { primer: p, hit, rc }));
+            }
+        }
+        (forward_primers, reverse_primers)
+    }
+
+    /// Whether a read with primers `f` & `r` has too short an insert.
+    fn is_dimer(&self, f: &Candidate, r: &Candidate) -> bool {
+        self.args
+            .min_insert
+            .is_some_and(|min| insert_length(&[*f, *r]) < min)
+    }
+
+    /// Annotate a read with a unique forward & reverse primer, returning the
+    /// `primers` description value.
+    fn classify_pair(
+        &self,
+        record: &fastq::Record,
+        f: &Candidate,
+        r: &Candidate,
+        annotation: &mut Annotation,
+        summary: &mut Summary,
+    ) -> String {
+        let segments = match self
+            .structure
+            .map(|rs| locate_segments(rs, record.seq().len(), f, r))
+        {
+            Some(Err(e)) => {
+                summary.add_structure_reject(&e.to_string());
+                return format!("structure:{e}");
+            }
+            segments => segments.and_then(Result::ok).unwrap_or_default(),
+        };
+        for c in [f, r] {
+            if summary
+                .variant_hits
+                .contains_key(&(c.primer.label().to_string(), 0))
+            {
+                summary.add_variant_hit(c.primer.label(), c.primer.variant());
             }
         }
+        annotation.segments = segments;
+        annotation.target = self.panel.and_then(|panel| {
+            panel
+                .get_target_by_names(f.primer.label(), r.primer.label())
+                .map(str::to_string)
+        });
+        let primers = assign(annotation, self.samples, f, r);
+        if self.is_dimer(f, r) {
+            summary.add_dimer(&primers);
+            return format!("dimer:{primers}");
+        }
+        let length_reject = self
+            .panel
+            .and_then(|panel| panel.lengths(annotation.pair.as_ref()?))
+            .and_then(|limits| limits.check(insert_length(&[*f, *r]), record.seq().len()));
+        if let Some(length) = length_reject {
+            // the rejects are reported by sample, but are not the sample's reads
+            summary.add_length_reject(annotation.sample.take().as_deref());
+            annotation.pair = None;
+            return format!("length:{length}");
+        }
+        let five_prime = if f.rc { r } else { f };
+        annotation.umi = self
+            .umi_layout
+            .and_then(|l| l.locate(five_prime.hit.start, record.seq().len()))
+            .map(|range| String::from_utf8_lossy(&record.seq()[range]).into_owned());
+        annotation.amplicon = self.references.and_then(|refs| {
+            let pair = annotation.pair.as_ref()?;
+            let expected = refs.get(annotation.target.as_deref(), pair)?;
+            Some(check_amplicon(
+                record.seq(),
+                f,
+                r,
+                expected,
+                self.args.min_identity,
+            ))
+        });
+        if let Some(m) = annotation.amplicon {
+            summary.add_amplicon_check(annotation.sample.as_deref(), m.on_target);
+        }
+        primers
+    }
+}
+
+/// The outputs of a `demux` run, which each read is written or added to.
+struct Outputs {
+    writer: fastq::Writer<io::Stdout>,
+    unaligned: Option<UnalignedWriter>,
+    assignments: Option<Box<dyn WriteAssignments>>,
+    count_matrix: CountMatrix,
+    umi_counts: UmiCounts,
+    dereplicator: Dereplicator,
+    fields: Vec<Field>,
+    reorient: bool,
+}
+
+impl Outputs {
+    /// Record a read rejected before classification in the assignments.
+    fn reject(&mut self, id: &str, reason: &str) {
+        if let Some(w) = &mut self.assignments {
+            let row = Assignment::rejected(id, reason);
+            w.write(&row).expect("Unable to write assignment.");
+        }
+    }
 
-        let mut primers_string = "".to_string();
-        if (forward_primers.len() == 1) & (reverse_primers.len() == 1) {
-            let f = forward_primers.pop().map(Primer::label).unwrap_or_default();
-            let r = reverse_primers.pop().map(Primer::label).unwrap_or_default();
-            primers_string = format!("primers:{f}-{r}");
+    /// Write a classified read, clipped & reoriented as asked, & add it to
+    /// the counts, molecules & ASVs.
+    fn write(&mut self, record: &fastq::Record, read: Classified, args: &DemuxArgs) {
+        let Classified {
+            mut annotation,
+            forward,
+            reverse,
+        } = read;
+        if let Some(w) = &mut self.assignments {
+            let row = Assignment::new(record.id(), &annotation, &forward, &reverse);
+            w.write(&row).expect("Unable to write assignment.");
+        }
+        let dimer = annotation.classification() == "dimer";
+        if args.exclude_dimers && dimer {
+            return;
+        }
+        if annotation.pair.is_some() {
+            self.count_matrix
+                .add(annotation.sample.as_deref(), annotation.target.as_deref());
+        }
+        if let (Some(sample), Some(umi)) = (&annotation.sample, &annotation.umi) {
+            self.umi_counts.add(sample, umi.as_bytes());
+        }
+        let range = match (&forward, &reverse, &annotation.pair) {
+            (Resolution::Unique(f), Resolution::Unique(r), Some(_)) if args.clip => {
+                clip_range(record.seq().len(), &[*f, *r])
+            }
+            _ => 0..record.seq().len(),
+        };
+        // dimers have no insert worth dereplicating
+        if let (Some(_), Some(sample), Some(_), Resolution::Unique(f), Resolution::Unique(r)) = (
+            args.asv.as_ref().filter(|_| !dimer),
+            &annotation.sample,
+            &annotation.pair,
+            &forward,
+            &reverse,
+        ) {
+            let insert = &record.seq()[clip_range(record.seq().len(), &[*f, *r])];
+            match annotation.orient {
+                Some(Orientation::Reverse) => self.dereplicator.add(sample, &dna::revcomp(insert)),
+                _ => self.dereplicator.add(sample, insert),
+            }
+        }
+        let (seq, qual) = (&record.seq()[range.clone()], &record.qual()[range]);
+        if self.reorient && annotation.pair.is_some() {
+            annotation.strand = annotation.orient;
+        }
+        let (seq, qual): (Cow<[u8]>, Cow<[u8]>) = if annotation.strand == Some(Orientation::Reverse)
+        {
+            (
+                dna::revcomp(seq).into(),
+                qual.iter().rev().copied().collect(),
+            )
         } else {
-            records_error += 1;
-            primers_string = "primers:invalid".to_string();
-            // continue;
-        }
-        // eprintln!("primers_string = '{primers_string}'");
-
-        let match_string: String = matches
-            .iter()
-            .filter(|(_, v)| **v == true)
-            .map(|(k, _)| k)
-            .join(":");
-        // eprintln!("{match_string}");
+            (seq.into(), qual.into())
+        };
+        if let Some(w) = &mut self.unaligned {
+            let _ = w.write(record.id(), &seq, &qual, &annotation);
+            return;
+        }
         let new_record = fastq::Record::with_attrs(
             record.id(),
-            Some(&primers_string),
-            record.seq(),
-            record.qual(),
+            Some(&annotation.describe(&self.fields)),
+            &seq,
+            &qual,
         );
-        let _ = writer.write_record(&new_record);
+        let _ = self.writer.write_record(&new_record);
     }
+}
 
-    eprintln!("records read: {}\nerrors: {}", records_read, records_error);
+fn demux(args: DemuxArgs) -> ExitCode {
+    let (Some(primers), Some(samples)) = (&args.primers, &args.samples) else {
+        eprintln!("Both --primers and --samples are required, on the command line or in --config.");
+        return ExitCode::FAILURE;
+    };
+    let primer_table = load_primers(primers).expect("Invalid primers file.");
+    let samples_table = load_samples(samples).expect("Unable to read samples table.");
+    let panel = args
+        .panel
+        .as_deref()
+        .map(|path| load_panel(path).expect("Unable to read panel file."));
+    let umi_layout = args.umi_length.map(|length| UmiLayout {
+        offset: args.umi_offset,
+        length,
+    });
+    let reorient = args.reorient || args.long_read;
+    let mut fields = args.fields.clone();
+    if primer_table.iter().any(|p| p.spacer().is_some()) && !fields.contains(&Field::Spacer) {
+        fields.push(Field::Spacer);
+    }
+    if args.panel.is_some() && !fields.contains(&Field::Target) {
+        fields.push(Field::Target);
+    }
+    if args.umi_length.is_some() && !fields.contains(&Field::Umi) {
+        fields.push(Field::Umi);
+    }
+    if reorient && !fields.contains(&Field::Strand) {
+        fields.push(Field::Strand);
+    }
+    let end_window = if args.long_read {
+        args.end_window.max(args.window)
+    } else {
+        args.end_window
+    };
+    let trimmer = make_trimmer(&args);
+    let trimming = !trimmer.adapters.is_empty() || trimmer.min_poly_g > 0;
+    let screen = if args.contaminants.is_empty() {
+        None
+    } else {
+        match load_screen(&args) {
+            Ok(screen) => Some(screen),
+            Err(e) => {
+                eprintln!("Unable to load contaminants: {e}");
+                return ExitCode::FAILURE;
+            }
+        }
+    };
+    let references = args
+        .amplicons
+        .as_deref()
+        .map(|path| load_amplicons(path).expect("Unable to read amplicons file."));
+
+    let structure = match args.structure.as_deref().map(str::parse::<ReadStructure>) {
+        Some(Err(e)) => {
+            eprintln!("Invalid read structure: {e}");
+            return ExitCode::FAILURE;
+        }
+        other => other.map(Result::unwrap),
+    };
+
+    let quality_match = QualityMatch {
+        max_penalty: args.max_penalty,
+        min_quality: args.min_quality,
+    };
+    let quality_match = (quality_match != QualityMatch::default()).then_some(quality_match);
+    let classifier = Classifier {
+        args: &args,
+        primers: &primer_table,
+        samples: &samples_table,
+        panel: panel.as_ref(),
+        references: references.as_ref(),
+        screen: screen.as_ref(),
+        structure: structure.as_ref(),
+        umi_layout,
+        quality_match,
+        resolver: Resolver {
+            margin: args.margin,
+            position_weight: args.position_weight,
+            barcode_weight: args.barcode_weight,
+        },
+        end_window,
+    };
+
+    let records = fastq::Reader::new(io::stdin()).records();
+    let mut mates = args.mate.as_ref().map(|path| {
+        fastq::Reader::from_file(path)
+            .expect("Unable to open mate file.")
+            .records()
+    });
+    let merger = Merger {
+        min_overlap: args.min_overlap,
+        max_mismatch_rate: args.max_mismatch_rate,
+    };
+    let mut unmerged_writer = args
+        .unmerged
+        .as_deref()
+        .map(|prefix| unmerged_writers(prefix).expect("Unable to create unmerged pair files."));
+    let unaligned = match args.format {
+        Format::Fastq => None,
+        Format::Sam => Some(UnalignedWriter::sam(io::stdout(), &samples_table)),
+        Format::Bam => Some(UnalignedWriter::bam(io::stdout(), &samples_table)),
+    }
+    .map(|w| w.expect("Unable to write SAM header."));
+
+    let assignments = args
+        .assignments
+        .as_ref()
+        .map(|path| assignments::create(path).expect("Unable to create assignments file."));
+    let mut outputs = Outputs {
+        writer: fastq::Writer::new(io::stdout()),
+        unaligned,
+        assignments,
+        count_matrix: CountMatrix::default(),
+        umi_counts: UmiCounts::default(),
+        dereplicator: Dereplicator::default(),
+        fields,
+        reorient,
+    };
+
+    let mut summary = Summary {
+        config: Some(config::to_toml(&args).expect("Unable to format configuration.")),
+        merge: mates.as_ref().map(|_| MergeStats::default()),
+        ..Default::default()
+    };
+
+    // list every variant of pooled primers, so that unused variants show up as 0
+    for p in primer_table.iter().filter(|p| p.variant() > 0) {
+        for v in 0..=p.variant() {
+            summary
+                .variant_hits
+                .entry((p.label().to_string(), v))
+                .or_insert(0);
+        }
+    }
+
+    let mut failed = false;
+    let mut record_number = 0;
+    // set when a record is skipped, so its mate is skipped by ID with the next record
+    let mut skip_mates = false;
+    for result in records {
+        record_number += 1;
+        let record = match result {
+            Ok(record) => record,
+            Err(e) => {
+                eprintln!("record {record_number}: {e}");
+                summary.parse_errors += 1;
+                summary.truncated |= input::is_truncation(&e);
+                // the ID of an unparsable record is unknown; use its number
+                outputs.reject(&format!("#{record_number}"), "unparsable");
+                if input::is_fatal(&e) || args.on_error == OnError::Fail {
+                    failed = true;
+                    break;
+                }
+                skip_mates = true;
+                continue;
+            }
+        };
+        summary.records_read += 1;
+        if let Err(reason) = record.check() {
+            eprintln!("record {record_number} ({}): {reason}", record.id());
+            summary.add_malformed(reason);
+            outputs.reject(record.id(), &format!("malformed:{reason}"));
+            if args.on_error == OnError::Fail {
+                failed = true;
+                break;
+            }
+            skip_mates = true;
+            continue;
+        }
+
+        let record = match &mut mates {
+            None => record,
+            Some(mates) => {
+                let mut next = mates.next();
+                if std::mem::take(&mut skip_mates) {
+                    while let Some(Ok(mate)) = &next {
+                        if mate_ids_match(record.id(), mate.id()) {
+                            break;
+                        }
+                        next = mates.next();
+                    }
+                }
+                let mate = match next {
+                    Some(Ok(mate)) => mate,
+                    Some(Err(e)) => {
+                        eprintln!("mate of record {record_number}: {e}");
+                        summary.parse_errors += 1;
+                        summary.truncated |= input::is_truncation(&e);
+                        failed = true;
+                        break;
+                    }
+                    None => {
+                        eprintln!("record {record_number} ({}): no mate", record.id());
+                        summary.truncated = true;
+                        failed = true;
+                        break;
+                    }
+                };
+                if !mate_ids_match(record.id(), mate.id()) {
+                    eprintln!(
+                        "record {record_number} ({}): mate ID {} differs",
+                        record.id(),
+                        mate.id()
+                    );
+                    summary.add_malformed("mate ID differs");
+                    failed = true;
+                    break;
+                }
+                if let Err(reason) = mate.check() {
+                    eprintln!("mate of record {record_number} ({}): {reason}", mate.id());
+                    summary.add_malformed(reason);
+                    outputs.reject(record.id(), &format!("malformed:{reason}"));
+                    if args.on_error == OnError::Fail {
+                        failed = true;
+                        break;
+                    }
+                    continue;
+                }
+                let merged = merger.merge(record.seq(), record.qual(), mate.seq(), mate.qual());
+                if let Some(stats) = &mut summary.merge {
+                    stats.add(merged.as_ref());
+                }
+                match merged {
+                    Some(m) => fastq::Record::with_attrs(record.id(), None, &m.seq, &m.qual),
+                    None => {
+                        outputs.reject(record.id(), "unmerged");
+                        if let Some([w1, w2]) = &mut unmerged_writer {
+                            let _ = w1.write_record(&record);
+                            let _ = w2.write_record(&mate);
+                        }
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let record = match trimming.then(|| trimmer.trim(record.seq())) {
+            Some((keep, Some(reason))) => {
+                summary.add_trimmed(reason);
+                fastq::Record::with_attrs(
+                    record.id(),
+                    record.desc(),
+                    &record.seq()[..keep],
+                    &record.qual()[..keep],
+                )
+            }
+            _ => record,
+        };
+
+        let mut split_hits = args.split.then(|| {
+            whole_read_hits(
+                &record,
+                &primer_table,
+                &args,
+                classifier.quality_match.as_ref(),
+            )
+        });
+        let pieces = match &split_hits {
+            Some(hits) => split_record(record, &primer_table, hits),
+            None => vec![record],
+        };
+        if pieces.len() > 1 {
+            summary.split.0 += 1;
+            summary.split.1 += pieces.len();
+            split_hits = None;
+        }
+
+        for record in pieces {
+            // an unsplit read keeps the hits found while looking for split points
+            let read = classifier.classify(&record, split_hits.take(), &mut summary);
+            outputs.write(&record, read, &args);
+        }
+    }
+    if let Some(w) = &mut outputs.unaligned {
+        w.finish().expect("Unable to finish SAM/BAM output.");
+    }
+    if let Some(mates) = &mut mates {
+        if skip_mates {
+            mates.next();
+        }
+        if !failed && mates.next().is_some() {
+            eprintln!("mate file has more records than the input");
+            failed = true;
+        }
+    }
+    if let Some(w) = &mut outputs.assignments {
+        w.finish().expect("Unable to finish assignments output.");
+    }
+
+    if let Some(path) = &args.counts {
+        std::fs::write(path, outputs.count_matrix.to_string())
+            .expect("Unable to write counts file.");
+    }
+
+    if let Some(path) = &args.molecules {
+        let file =
+            io::BufWriter::new(File::create(path).expect("Unable to create molecules file."));
+        outputs
+            .umi_counts
+            .write_molecules(file, args.umi_distance)
+            .expect("Unable to write molecules file.");
+    }
+    if let Some(prefix) = &args.asv {
+        write_asvs(
+            prefix,
+            &outputs.dereplicator,
+            &samples_table,
+            args.min_abundance,
+        )
+        .expect("Unable to write ASV files.");
+    }
+
+    eprint!("{summary}");
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
 }
@@ -0,0 +1,288 @@
+use bio::alphabets::dna;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+const PRIMERS: &str = "oVK001\tGATACAGG\tGAT\tF\n\
+                       oVK002\tCCATTGAC\tCCA\tF\n\
+                       oVK010\tTTGGCCAA\tTTG\tR\n";
+const SAMPLES: &str = "\toVK010\noVK001\tS1\noVK002\tS2\n";
+
+/// A fresh directory for the files of test `name`, holding the primers & samples.
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("myfq-{name}-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("primers.tsv"), PRIMERS).unwrap();
+    fs::write(dir.join("samples.tsv"), SAMPLES).unwrap();
+    dir
+}
+
+/// Run `myfq demux` in `dir` with `args`, reading `input` from stdin.
+fn demux(dir: &PathBuf, args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_myfq"))
+        .current_dir(dir)
+        .args(["demux", "-p", "primers.tsv", "-s", "samples.tsv"])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/// The IDs & descriptions of the FASTQ records in `output`.
+fn headers(output: &Output) -> Vec<String> {
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .step_by(4)
+        .map(|l| l.trim_start_matches('@').to_string())
+        .collect()
+}
+
+fn revcomp(seq: &str) -> String {
+    String::from_utf8(dna::revcomp(seq.as_bytes())).unwrap()
+}
+
+fn fastq(records: &[(&str, &str)]) -> String {
+    records
+        .iter()
+        .map(|(id, seq)| format!("@{id}\n{seq}\n+\n{}\n", "I".repeat(seq.len())))
+        .collect()
+}
+
+#[test]
+fn skipped_record_skips_its_mate() {
+    let dir = scratch("skip-mate");
+    let r1 = "GATACAGGACGTTGCAGTCAGGATC";
+    let r2 = "TTGGCCAAATGGATCCTGACTGCAA";
+    fs::write(
+        dir.join("r2.fq"),
+        fastq(&[("p1/2", r2), ("p2/2", r2), ("p3/2", r2)]),
+    )
+    .unwrap();
+    // p2 has too few qualities, & a stray line precedes p3
+    let input = format!(
+        "{}@p2/1\n{r1}\n+\nIIII\nstray\n{}",
+        fastq(&[("p1/1", r1)]),
+        fastq(&[("p3/1", r1)])
+    );
+    let output = demux(&dir, &["--mate", "r2.fq"], &input);
+    assert!(output.status.success());
+    let ids: Vec<_> = headers(&output)
+        .iter()
+        .map(|h| h.split(' ').next().unwrap().to_string())
+        .collect();
+    assert_eq!(ids, ["p1/1", "p3/1"]);
+    assert!(headers(&output)[1].contains("sample=S1"));
+}
+
+#[test]
+fn long_read_consensus_is_not_flipped_twice() {
+    let dir = scratch("long-read-strand");
+    fs::write(
+        dir.join("primers.tsv"),
+        "oVK001\tGATACAGGTCAGTCCATGGAAC\tGAT\tF\n\
+         oVK010\tTTGGCCAACGTAGCTAGGATCT\tTTG\tR\n",
+    )
+    .unwrap();
+    let insert = "CAGATTTTCATATTATGCAGAAAATCTACTTCGCCTGATACGAGTCGGTTATCTTCGGAT";
+    // the read is the reverse complement of oVK001, insert, oVK010rc
+    let read = format!(
+        "TTGGCCAACGTAGCTAGGATCT{}GTTCCATGGACTGACCTGTATC",
+        revcomp(insert)
+    );
+    let output = demux(&dir, &["--long-read", "--clip"], &fastq(&[("L1", &read)]));
+    assert!(output.status.success());
+    assert!(headers(&output)[0].contains("orient=- "));
+    assert!(headers(&output)[0].ends_with("strand=-"));
+    fs::write(dir.join("out.fq"), &output.stdout).unwrap();
+    let consensus = Command::new(env!("CARGO_BIN_EXE_myfq"))
+        .current_dir(&dir)
+        .args(["consensus", "out.fq"])
+        .output()
+        .unwrap();
+    let fasta = String::from_utf8(consensus.stdout).unwrap();
+    assert_eq!(fasta.lines().nth(1), Some(insert));
+}
+
+#[test]
+fn concatemer_is_split_and_each_piece_assigned() {
+    let dir = scratch("split");
+    let concatemer = "GATACAGGACGTTGCAGTCAGGATCCATTTGGCCAACCCCATTGACTTTTGGGGCCCCAAAATTGGCCAA";
+    let single = "GATACAGGACGTTGCAGTCAGGATCCATTTGGCCAA";
+    let input = fastq(&[("c1", concatemer), ("s1", single)]);
+    let output = demux(&dir, &["--split"], &input);
+    assert!(output.status.success());
+    let headers = headers(&output);
+    assert_eq!(headers.len(), 3);
+    assert!(headers[0].starts_with("c1_1 sample=S1 primers=oVK001-oVK010 "));
+    assert!(headers[1].starts_with("c1_2 sample=S2 primers=oVK002-oVK010 "));
+    assert!(headers[2].starts_with("s1 sample=S1 primers=oVK001-oVK010 "));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("split reads: 1 into 2"));
+}
+
+#[test]
+fn length_rejects_are_reported_but_not_counted() {
+    let dir = scratch("lengths");
+    fs::write(
+        dir.join("panel.tsv"),
+        "oVK001\toVK010\tampA\tinsert=15-40\n",
+    )
+    .unwrap();
+    let input = fastq(&[
+        ("a", "GATACAGGACGTTGCAGTCAGGATCCATTTGGCCAA"),
+        ("b", "GATACAGGACGTATTGGCCAA"),
+    ]);
+    let args = [
+        "--panel",
+        "panel.tsv",
+        "--counts",
+        "counts.tsv",
+        "--asv",
+        "asv",
+    ];
+    let output = demux(&dir, &args, &input);
+    assert!(output.status.success());
+    assert!(headers(&output)[1].starts_with("b sample=. primers=length:insert "));
+    let summary = String::from_utf8_lossy(&output.stderr);
+    assert!(summary.contains("length rejects: 1\n  S1: 1\n"));
+    let counts = fs::read_to_string(dir.join("counts.tsv")).unwrap();
+    assert_eq!(counts, "sample\tampA\nS1\t1\n");
+    let asvs = fs::read_to_string(dir.join("asv.fasta")).unwrap();
+    assert_eq!(asvs.lines().nth(1), Some("ACGTTGCAGTCAGGATCCAT"));
+    assert_eq!(asvs.lines().count(), 2);
+
+    fs::write(dir.join("out.fq"), &output.stdout).unwrap();
+    let stats = Command::new(env!("CARGO_BIN_EXE_myfq"))
+        .current_dir(&dir)
+        .args(["stats", "out.fq"])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&stats.stdout).ends_with("samples:\n  S1: 1\n"));
+    let sam = demux(&dir, &["--panel", "panel.tsv", "--format", "sam"], &input);
+    let sam = String::from_utf8_lossy(&sam.stdout);
+    let b = sam.lines().find(|l| l.starts_with("b\t")).unwrap();
+    assert!(!b.contains("RG:Z:"));
+}
+
+#[test]
+fn subcommands_fail_without_panicking() {
+    let dir = scratch("subcommand-errors");
+    fs::write(dir.join("bad.fq"), "@r1\nACGT\n").unwrap();
+    for args in [
+        &["stats", "missing.fq"][..],
+        &["stats", "bad.fq"],
+        &["consensus", "missing.fq"],
+        &["primers", "--primers", "missing.tsv"],
+        &["layout", "--samples", "missing.tsv"],
+    ] {
+        let output = Command::new(env!("CARGO_BIN_EXE_myfq"))
+            .current_dir(&dir)
+            .args(args)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1), "{args:?}");
+    }
+}
+
+#[test]
+fn spacer_field_is_added_for_primers_with_spacers() {
+    let dir = scratch("spacer");
+    fs::write(
+        dir.join("primers.tsv"),
+        PRIMERS.replacen("\tF\n", "\tF\t0-3\n", 1),
+    )
+    .unwrap();
+    let input = fastq(&[("r1", "TCGATACAGGAAAAAAAAAAAAAAAAAAATTGGCCAACC")]);
+    let output = demux(&dir, &[], &input);
+    assert!(output.status.success());
+    assert!(headers(&output)[0].ends_with(" spacer=2"));
+}
+
+#[test]
+fn sample_counts_in_several_targets() {
+    let dir = scratch("multiplex");
+    // S1 is amplified by both forward primers
+    fs::write(
+        dir.join("samples.tsv"),
+        "\toVK010\noVK001\tS1\noVK002\tS1\n",
+    )
+    .unwrap();
+    fs::write(
+        dir.join("panel.tsv"),
+        "oVK001\toVK010\tampA\noVK002\toVK010\tampB\n",
+    )
+    .unwrap();
+    let input = fastq(&[
+        ("a1", "GATACAGGACGTTGCAGTCAGGATCCATTTGGCCAA"),
+        ("a2", "GATACAGGACGTTGCAGTCAGGATCCATTTGGCCAA"),
+        ("b1", "CCATTGACTTTTGGGGCCCCAAAATTGGCCAA"),
+    ]);
+    let output = demux(
+        &dir,
+        &["--panel", "panel.tsv", "--counts", "counts.tsv"],
+        &input,
+    );
+    assert!(output.status.success());
+    let counts = fs::read_to_string(dir.join("counts.tsv")).unwrap();
+    assert_eq!(counts, "sample\tampA\tampB\nS1\t2\t1\n");
+}
+
+#[test]
+fn malformed_mate_is_skipped() {
+    let dir = scratch("bad-mate");
+    let r1 = "GATACAGGACGTTGCAGTCAGGATC";
+    let r2 = "TTGGCCAAATGGATCCTGACTGCAA";
+    // the mate of p2 has too few qualities
+    let mates = format!(
+        "{}@p2/2\n{r2}\n+\nIIII\n{}",
+        fastq(&[("p1/2", r2)]),
+        fastq(&[("p3/2", r2)])
+    );
+    fs::write(dir.join("r2.fq"), mates).unwrap();
+    let input = fastq(&[("p1/1", r1), ("p2/1", r1), ("p3/1", r1)]);
+    let output = demux(&dir, &["--mate", "r2.fq"], &input);
+    assert!(output.status.success());
+    let ids: Vec<_> = headers(&output)
+        .iter()
+        .map(|h| h.split(' ').next().unwrap().to_string())
+        .collect();
+    assert_eq!(ids, ["p1/1", "p3/1"]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("malformed: 1"));
+
+    let output = demux(&dir, &["--mate", "r2.fq", "--on-error", "fail"], &input);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn dimers_are_not_dereplicated() {
+    let dir = scratch("dimer-asv");
+    let input = fastq(&[
+        ("a", "GATACAGGACGTTGCAGTCAGGATCCATTTGGCCAA"),
+        ("d", "GATACAGGACTTGGCCAA"),
+    ]);
+    let output = demux(&dir, &["--min-insert", "10", "--asv", "asv"], &input);
+    assert!(output.status.success());
+    assert!(headers(&output)[1].starts_with("d sample=S1 primers=dimer:oVK001-oVK010 "));
+    let asvs = fs::read_to_string(dir.join("asv.fasta")).unwrap();
+    assert_eq!(asvs.lines().nth(1), Some("ACGTTGCAGTCAGGATCCAT"));
+    assert_eq!(asvs.lines().count(), 2);
+}
+
+#[test]
+fn dimer_with_adapter_read_through_is_not_a_chimera() {
+    let dir = scratch("dimer-read-through");
+    let input = fastq(&[("d", "GATACAGGACTTGGCCAAAGATCGGAAGAGCACACGTCTGAACTCCAG")]);
+    let output = demux(&dir, &["--min-insert", "10", "--end-window", "5"], &input);
+    assert!(output.status.success());
+    assert!(headers(&output)[0].starts_with("d sample=S1 primers=dimer:oVK001-oVK010 "));
+}
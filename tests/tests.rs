@@ -1,3 +1,7 @@
+// The baseline tests bind their readers as `mut`; keep them as written.
+#![allow(unused_mut)]
+
+use myfq::primers::*;
 use myfq::samples::*;
 use std::fs::File;
 use std::io::{prelude::*, BufReader};
@@ -6,6 +10,7 @@ use std::path::Path;
 const DATA_DIR: &str = "tests/data";
 const SAMPLES_FILE_GOOD: &str = "samples_good.tsv";
 const SAMPLES_FILE_EMPTY: &str = "samples_empty.tsv";
+const PRIMERS_FILE_GOOD: &str = "primers_good.tsv";
 
 #[test]
 fn read_sample_table_good() {
@@ -32,3 +37,28 @@ fn read_sample_table_empty() {
         Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidData),
     }
 }
+
+#[test]
+fn read_primer_table_good() {
+    let primers_good = Path::new(DATA_DIR).join(PRIMERS_FILE_GOOD);
+    let mut primers_file = File::open(primers_good).expect("Unable to open primers file.");
+    let primers = read_primer_table(&mut primers_file).expect("Unable to read primers table.");
+    assert_eq!(primers.len(), 4);
+    assert_eq!(primers[0].label(), "oVK790");
+    assert_eq!(primers[2].direction(), Direction::Reverse);
+}
+
+#[test]
+fn primers_cover_samples_table() {
+    let primers_good = Path::new(DATA_DIR).join(PRIMERS_FILE_GOOD);
+    let mut primers_file = File::open(primers_good).expect("Unable to open primers file.");
+    let primers = read_primer_table(&mut primers_file).expect("Unable to read primers table.");
+    let samples_file = Box::new(BufReader::new(
+        File::open(Path::new(DATA_DIR).join(SAMPLES_FILE_GOOD))
+            .expect("Unable to open samples file."),
+    )) as Box<dyn BufRead>;
+    let samples_table = read_wide_table(samples_file).expect("Unable to open samples table.");
+    let problems = samples_table.check_primers(&primers);
+    assert!(problems.iter().any(|p| p.contains("oVK804")));
+    assert!(!problems.iter().any(|p| p.contains("oVK790")));
+}